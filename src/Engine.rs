@@ -1,11 +1,18 @@
 use std::ops::{BitAnd};
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use chess::{BitBoard, Board, CacheTable, ChessMove, Color, EMPTY, MoveGen, Piece, Square};
+use chess::{BitBoard, Board, ChessMove, Color, EMPTY, MoveGen, Piece, Square};
 use chess::Color::White;
 use Color::Black;
 
-use crate::Engine::NodeType::AllNode;
+use crate::move_picker::{MovePicker, is_quiet};
+use crate::position::Position;
+use crate::transposition_table::{NodeType, TranspositionEntry, TranspositionTable};
+use crate::time_manager::TimeManager;
 
 const WHITE_PAWN: [i32; 64] = [
     // Rank 1
@@ -135,29 +142,105 @@ const FLIP: [usize; 64] = [
     0, 1, 2, 3, 4, 5, 6, 7
 ];
 
-#[derive(Clone, Copy, PartialOrd, PartialEq, Debug)]
-struct TranspositionEntry {
-    mov: Option<ChessMove>,
-    score: Option<i32>,
-    node_type: NodeType,
-    depth: u16,
+/// Limits of a single search, as given by the "go" command.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SearchLimits {
+    pub depth: Option<u16>,
+    pub nodes: Option<u64>,
+    /// Stop as soon as a mate in this many moves is found.
+    pub mate: Option<u16>,
+    pub time: Option<TimeManager>,
 }
 
+impl SearchLimits {
+    pub fn is_bounded(&self) -> bool {
+        self.depth.is_some() || self.nodes.is_some() || self.mate.is_some()
+    }
+}
+
+
+/// Flags the UCI handler uses to steer a running search.
+#[derive(Default)]
+pub struct SearchSignals {
+    pub stop: AtomicBool,
+    /// Set while pondering, the time limits only start to count once "ponderhit" clears it.
+    pub ponder: AtomicBool,
+    // Nodes searched by all threads, each one adds its count every now and then.
+    nodes: AtomicU64,
+}
+
+/// Progress of a running search, streamed to the UCI handler.
+pub enum SearchReport {
+    Iteration(IterationInfo),
+    /// The root move currently searched, `number` starts at 1.
+    CurrentMove { depth: u16, mov: ChessMove, number: usize },
+}
+
+/// The result of a finished iteration of `iterative_deepening`, one per MultiPV line.
+#[derive(Clone)]
+pub struct IterationInfo {
+    pub depth: u16,
+    /// Deepest ply reached in this iteration, over all lines searched so far.
+    pub seldepth: u16,
+    /// Rank of this line, 1 is the best.
+    pub multi_pv: usize,
+    pub score: i32,
+    /// Summed over all threads.
+    pub nodes: u64,
+    pub time: Duration,
+    /// Permille of the transposition table in use.
+    pub hashfull: u16,
+    pub pv: Vec<ChessMove>,
+}
 
-#[derive(Clone, Copy, PartialOrd, PartialEq, Debug)]
-enum NodeType {
-    PVNode,
-    AllNode,
-    CutNode,
+impl IterationInfo {
+    pub fn nps(&self) -> u64 {
+        self.nodes * 1000 / (self.time.as_millis() as u64).max(1)
+    }
 }
 
 
 pub struct Engine {
-    pub pos_counter: u32,
+    pub pos_counter: u64,
     pub cut_off_counter: u32,
-    transposition_table: CacheTable<TranspositionEntry>,
-    repeat_table: CacheTable<u16>,
-    pv_table: [ChessMove; 100],
+    // Shared with the helper threads, which only search to fill it for the main thread.
+    transposition_table: Arc<TranspositionTable>,
+    helpers: Vec<Engine>,
+    // 0 for the main thread, helpers count from 1.
+    thread_index: usize,
+    // The part of `pos_counter` already added to the shared count in `signals`.
+    published_nodes: u64,
+    // Line 1 of the last finished iteration.
+    result: Option<IterationInfo>,
+    // Two quiet moves per ply that recently caused a beta cutoff there, the newer one first.
+    killers: [[Option<ChessMove>; 2]; MAX_PLY],
+    // How well quiet moves did so far, indexed by side to move, from and to square.
+    history_scores: [[[i32; 64]; 64]; 2],
+    // The quiet move that last refuted a move, indexed by that move's piece and destination.
+    countermoves: [Option<ChessMove>; PIECE_SQUARES],
+    // How well quiet moves did after the move one and two plies earlier, indexed by the plies back,
+    // then the piece and destination of the earlier move and of the move itself.
+    continuation_history: Vec<i32>,
+    // Piece and destination of the move made at each ply of the search path, `None` for a null move.
+    move_stack: [Option<usize>; MAX_PLY],
+    // Hashes of the game's positions before the root, followed by the ones on the current search path.
+    position_history: Vec<u64>,
+    game_length: usize,
+    // Triangular PV table, row `ply` holds the best line found from that ply on.
+    pv_table: [[ChessMove; MAX_PLY]; MAX_PLY],
+    pv_length: [usize; MAX_PLY],
+    seldepth: u16,
+    signals: Arc<SearchSignals>,
+    pondering: bool,
+    completed_depth: u16,
+    node_limit: u64,
+    // Null-move pruning and late move reductions, both off for "go mate" since they can hide a mate.
+    pruning: bool,
+    // Whether stored bounds may end the search of a node, only turned off to compare node counts.
+    transposition_cutoffs: bool,
+    time: Option<TimeManager>,
+    start: Instant,
+    reports: Option<Sender<SearchReport>>,
 }
 
 
@@ -169,7 +252,59 @@ const QUEEN_VALUE: i32 = 900;
 
 const KING_VALUE: i32 = 20000;
 
-const PIECE_VALUES: [i32; 6] = [
+const MAX_PLY: usize = 128;
+
+// Being checkmated at ply n scores -MATE_SCORE + n, so shorter mates are preferred.
+const MATE_SCORE: i32 = 32_000;
+// Every score beyond this bound is a forced mate.
+const MATE_BOUND: i32 = MATE_SCORE - MAX_PLY as i32;
+// Bounds every score, unlike i32::MIN it can be negated.
+const INFINITY: i32 = MATE_SCORE + 1;
+
+// From this depth on the root is searched with a window of ASPIRATION_WINDOW around the score of
+// the previous iteration, which is doubled on every fail high or low.
+const ASPIRATION_MIN_DEPTH: u16 = 4;
+const ASPIRATION_WINDOW: i32 = 25;
+
+// A position is drawn once this many half moves were played without a capture or pawn move.
+const FIFTY_MOVE_LIMIT: u16 = 100;
+
+// Null-move pruning starts at this depth and reduces by at least NULL_MOVE_REDUCTION plies, one more
+// for every NULL_MOVE_DEPTH_STEP plies of depth and every NULL_MOVE_MARGIN_STEP the evaluation is
+// above beta, up to NULL_MOVE_MAX_MARGIN_PLIES of the latter.
+const NULL_MOVE_MIN_DEPTH: u16 = 3;
+const NULL_MOVE_REDUCTION: u16 = 2;
+const NULL_MOVE_DEPTH_STEP: u16 = 6;
+const NULL_MOVE_MARGIN_STEP: i32 = 200;
+const NULL_MOVE_MAX_MARGIN_PLIES: i32 = 2;
+// From this depth on a null-move cutoff is only trusted after a reduced search without null moves.
+const NULL_MOVE_VERIFICATION_DEPTH: u16 = 8;
+
+// Late move reductions start at this depth and with this move index, the reduction grows with the
+// logarithm of both.
+const LMR_MIN_DEPTH: u16 = 3;
+const LMR_MIN_MOVES: usize = 3;
+const LMR_BASE: f64 = 0.75;
+const LMR_DIVISOR: f64 = 2.25;
+// Every this much history score reduces one ply less, negative scores reduce more.
+const LMR_HISTORY_DIVISOR: i32 = 8192;
+
+// History scores stay within +-HISTORY_MAX, getting close to it slows down further changes.
+const HISTORY_MAX: i32 = 16_384;
+
+// A moving piece of either colour together with its destination, what the countermove and
+// continuation tables are keyed by.
+const PIECE_SQUARES: usize = 12 * 64;
+// Continuation history looks this many plies back.
+const CONTINUATION_PLIES: usize = 2;
+
+// Lets every other helper thread search one ply deeper than the main thread.
+const HELPER_DEPTH_OFFSETS: [u16; 2] = [0, 1];
+
+// Root moves are only reported with "currmove" once the search has been running this long.
+const CURRMOVE_DELAY: Duration = Duration::from_secs(1);
+
+pub const PIECE_VALUES: [i32; 6] = [
     PAWN_VALUE,
     KNIGHT_VALUE,
     BISHOP_VALUE,
@@ -226,11 +361,11 @@ pub fn evaluate(board: &Board) -> i32 {
     score += iterate_bitboard(&mut white_king, |square: Square| WHITE_KING[square.to_index()]);
     score += iterate_bitboard(&mut black_king, |square: Square| -WHITE_KING[FLIP[square.to_index()]]);
 
-    return if board.side_to_move() == White {
+    if board.side_to_move() == White {
         score
     } else {
         score * -1
-    };
+    }
 }
 
 #[inline]
@@ -240,95 +375,475 @@ fn iterate_bitboard(bb: &mut BitBoard, f: fn(Square) -> i32) -> i32 {
         score += f(i);
     }
 
-    return score;
+    score
 }
 
 impl Engine {
-    pub fn new() -> Engine {
-        return Engine {
+    /// Creates an engine whose transposition table uses at most `hash_mb` megabytes. It searches
+    /// on a single thread until `set_threads` is called.
+    pub fn new(hash_mb: usize) -> Engine {
+        Engine::with_table(Arc::new(TranspositionTable::new(hash_mb)), 0)
+    }
+
+    fn with_table(transposition_table: Arc<TranspositionTable>, thread_index: usize) -> Engine {
+        Engine {
             pos_counter: 0,
             cut_off_counter: 0,
-            transposition_table: CacheTable::new(33554432, TranspositionEntry { mov: None, score: None, node_type: AllNode, depth: 0 }),
-            repeat_table: CacheTable::new(33554432, 0u16),
-            pv_table: [ChessMove::default(); 100],
-        };
+            transposition_table,
+            helpers: Vec::new(),
+            thread_index,
+            published_nodes: 0,
+            result: None,
+            killers: [[None; 2]; MAX_PLY],
+            history_scores: [[[0; 64]; 64]; 2],
+            countermoves: [None; PIECE_SQUARES],
+            continuation_history: vec![0; CONTINUATION_PLIES * PIECE_SQUARES * PIECE_SQUARES],
+            move_stack: [None; MAX_PLY],
+            position_history: Vec::new(),
+            game_length: 0,
+            pv_table: [[ChessMove::default(); MAX_PLY]; MAX_PLY],
+            pv_length: [0; MAX_PLY],
+            seldepth: 0,
+            signals: Arc::new(SearchSignals::default()),
+            pondering: false,
+            completed_depth: 0,
+            node_limit: u64::MAX,
+            pruning: true,
+            transposition_cutoffs: true,
+            time: None,
+            start: Instant::now(),
+            reports: None,
+        }
     }
 
+    /// Forgets everything learned in previous searches.
+    pub fn new_game(&mut self) {
+        self.clear_hash();
+        self.reset_heuristics();
+        for helper in &mut self.helpers {
+            helper.reset_heuristics();
+        }
+    }
+
+    fn reset_heuristics(&mut self) {
+        self.pv_table = [[ChessMove::default(); MAX_PLY]; MAX_PLY];
+        self.pv_length = [0; MAX_PLY];
+        self.killers = [[None; 2]; MAX_PLY];
+        self.history_scores = [[[0; 64]; 64]; 2];
+        self.countermoves = [None; PIECE_SQUARES];
+        self.continuation_history.fill(0);
+    }
+
+    pub fn clear_hash(&mut self) {
+        self.transposition_table.clear();
+    }
+
+    pub fn resize_hash(&mut self, hash_mb: usize) {
+        let threads = self.helpers.len() + 1;
+        // Drop the old table first, so both never take up memory at the same time.
+        self.helpers.clear();
+        self.transposition_table = Arc::new(TranspositionTable::new(0));
+        self.transposition_table = Arc::new(TranspositionTable::new(hash_mb));
+        self.set_threads(threads);
+    }
+
+    /// Searches with `threads` threads in total, the helpers share the transposition table.
+    pub fn set_threads(&mut self, threads: usize) {
+        let helpers = threads.max(1) - 1;
+        self.helpers.truncate(helpers);
+        while self.helpers.len() < helpers {
+            self.helpers.push(Engine::with_table(self.transposition_table.clone(), self.helpers.len() + 1));
+        }
+    }
+
+    /// Searches `position` with increasing depth until a limit is hit or the stop signal is set. Every
+    /// depth reports the best `multi_pv` lines, each searched with the moves of the better lines excluded.
+    /// `history` holds the hashes of the positions played before `position`, oldest first.
+    ///
+    /// The helper threads search the same position without reporting anything. Once this thread is
+    /// done they are stopped, and a helper that got further reports its line as the final result.
+    #[allow(clippy::too_many_arguments)]
+    pub fn iterative_deepening(&mut self,  position: Position, history: Vec<u64>, tx: Sender<SearchReport>, limits: SearchLimits, search_moves: Vec<ChessMove>, multi_pv: usize, signals: Arc<SearchSignals>) {
+        self.transposition_table.new_search();
+
+        let mut helpers = std::mem::take(&mut self.helpers);
+        thread::scope(|scope| {
+            for helper in helpers.iter_mut() {
+                // Only the main thread keeps an eye on the clock.
+                let helper_limits = SearchLimits { time: None, ..limits };
+                helper.start_search(history.clone(), &helper_limits, None, signals.clone());
+                let search_moves = &search_moves;
+                scope.spawn(move || helper.search(position, &helper_limits, search_moves, 1));
+            }
+
+            self.start_search(history, &limits, Some(tx.clone()), signals);
+            self.search(position, &limits, &search_moves, multi_pv);
+            self.signals.stop.store(true, Ordering::Relaxed);
+        });
 
-    pub fn iterative_deepening(&mut self,  board: Board, tx: Sender<(i32, ChessMove, u16, u32)>, rx: Receiver<()>) {
-        let mut first_guess = 0;
-        let mut best_move = ChessMove::default();
+        // Results from different depths only compare for a single line.
+        if multi_pv == 1 {
+            let best_helper = helpers.iter()
+                .filter_map(|helper| helper.result.as_ref())
+                .max_by_key(|result| (result.depth, result.score));
+            if let (Some(helper_result), Some(result)) = (best_helper, &self.result) {
+                if (helper_result.depth, helper_result.score) > (result.depth, result.score) {
+                    let info = IterationInfo {
+                        nodes: self.signals.nodes.load(Ordering::Relaxed),
+                        time: self.start.elapsed(),
+                        hashfull: self.transposition_table.hashfull(),
+                        ..helper_result.clone()
+                    };
+                    let _ = tx.send(SearchReport::Iteration(info));
+                }
+            }
+        }
+        self.helpers = helpers;
+    }
+
+    fn start_search(&mut self, history: Vec<u64>, limits: &SearchLimits, reports: Option<Sender<SearchReport>>, signals: Arc<SearchSignals>) {
+        self.pondering = signals.ponder.load(Ordering::Relaxed);
+        self.signals = signals;
+        self.pos_counter = 0;
+        self.published_nodes = 0;
+        self.completed_depth = 0;
+        self.result = None;
+        self.node_limit = limits.nodes.unwrap_or(u64::MAX);
+        self.pruning = limits.mate.is_none();
+        self.time = limits.time;
+        self.start = Instant::now();
+        self.reports = reports;
+        self.game_length = history.len();
+        self.position_history = history;
+
+        // Killers are tied to the plies of the last search, the history only loses some weight.
+        self.killers = [[None; 2]; MAX_PLY];
+        for score in self.history_scores.iter_mut().flatten().flatten().chain(&mut self.continuation_history) {
+            *score /= 2;
+        }
+    }
+
+    fn search(&mut self, position: Position, limits: &SearchLimits, search_moves: &[ChessMove], multi_pv: usize) {
+        let mut max_depth = limits.depth.unwrap_or(99).clamp(1, 99);
+        if let Some(mate) = limits.mate {
+            // Checkmate is only detected at full-width nodes, so a mate in n moves needs 2n plies
+            // without any pruning.
+            max_depth = (2 * u32::from(mate.max(1))).min(max_depth as u32) as u16;
+        }
+
+        let mut candidates = search_moves.to_vec();
+        if candidates.is_empty() {
+            candidates = MoveGen::new_legal(&position.board).collect();
+        }
+        let lines = multi_pv.clamp(1, candidates.len().max(1));
+        // Counted without "searchmoves", a single move given there still gets the time to be searched.
+        let single_legal_move = MoveGen::new_legal(&position.board).len() == 1;
+
+        let mut last_result: Option<(i32, ChessMove)> = None;
+        let mut stable_iterations = 0;
+        // The score of every line in the previous iteration, the aspiration windows are centred on them.
+        let mut line_scores = vec![None; lines];
+
+        let first_depth = 1 + HELPER_DEPTH_OFFSETS[self.thread_index % HELPER_DEPTH_OFFSETS.len()];
+        'iterations: for d in first_depth.min(max_depth)..=max_depth {
+            let mut best_line = (0, ChessMove::default());
+            let mut excluded = Vec::new();
+            // Like the node count, the reported seldepth covers all lines of the iteration so far.
+            self.seldepth = 0;
+
+            for line in 1..=lines {
+                let mut root_moves = search_moves.to_vec();
+                if !excluded.is_empty() {
+                    root_moves = candidates.iter().filter(|&mov| !excluded.contains(mov)).copied().collect();
+                }
+
+                let (score, best_move) = self.aspiration_search(position, d, line_scores[line - 1], &root_moves);
+
+                // An interrupted iteration is incomplete, the last finished depth has already been reported.
+                if self.is_stopped() {
+                    break 'iterations;
+                }
+                line_scores[line - 1] = Some(score);
+
+                if line == 1 {
+                    self.completed_depth = d;
+                    best_line = (score, best_move);
+                }
+                excluded.push(best_move);
+
+                let info = IterationInfo {
+                    depth: d,
+                    seldepth: self.seldepth,
+                    multi_pv: line,
+                    score,
+                    nodes: self.total_nodes(),
+                    time: self.start.elapsed(),
+                    hashfull: self.transposition_table.hashfull(),
+                    pv: self.pv_table[0][..self.pv_length[0]].to_vec(),
+                };
+                if line == 1 {
+                    self.result = Some(info.clone());
+                }
+                if let Some(reports) = &self.reports {
+                    if reports.send(SearchReport::Iteration(info)).is_err() {
+                        break 'iterations;
+                    }
+                }
+            }
+
+            let (score, best_move) = best_line;
+
+            if let Some(mate) = limits.mate {
+                if mate_in(score).is_some_and(|moves| moves > 0 && moves <= mate as i32) {
+                    break;
+                }
+            }
+
+            let mut score_drop = 0;
+            if let Some((last_score, last_move)) = last_result {
+                if last_move == best_move {
+                    stable_iterations += 1;
+                } else {
+                    stable_iterations = 0;
+                }
+                score_drop = last_score - score;
+            }
+            last_result = Some((score, best_move));
 
-        for d in 1..100 {
-            (first_guess, best_move) = self.pvs(board, i32::MIN, i32::MAX, d);
-            tx.send((first_guess, best_move, d, self.pos_counter));
-            self.repeat_table = CacheTable::new(33554432, 0u16);
-            match rx.try_recv() {
-                Ok(_) | Err(TryRecvError::Disconnected) => {
+            self.poll_ponderhit();
+            if let (Some(time), false) = (self.time, self.pondering) {
+                // With a single legal move there is nothing to think about.
+                if single_legal_move || time.should_stop(stable_iterations, score_drop) {
                     break;
                 }
-                Err(TryRecvError::Empty) => {}
             }
         }
-        //(first_guess, best_move) = self.pvs(board, i32::MIN, i32::MAX, depth);
 
+        self.publish_nodes();
+        self.reports = None;
+    }
 
-        let mut pv = Vec::new();
-        for i in 0..100 {
-            if self.pv_table[i] == ChessMove::default() {
-                break;
+    /// Searches the root with a narrow window around `previous_score` first, only a score outside of
+    /// it needs another search with the failing side of the window widened.
+    fn aspiration_search(&mut self, position: Position, depth: u16, previous_score: Option<i32>, root_moves: &[ChessMove]) -> (i32, ChessMove) {
+        let mut delta = ASPIRATION_WINDOW;
+        let (mut alpha, mut beta) = match previous_score {
+            Some(score) if depth >= ASPIRATION_MIN_DEPTH => ((score - delta).max(-INFINITY), (score + delta).min(INFINITY)),
+            _ => (-INFINITY, INFINITY),
+        };
+
+        loop {
+            let (score, best_move) = self.pvs(position, alpha, beta, depth, 0, root_moves);
+            if self.is_stopped() {
+                return (score, best_move);
             }
-            pv.push(self.pv_table[i]);
+
+            if score <= alpha && alpha > -INFINITY {
+                alpha = (score - delta).max(-INFINITY);
+            } else if score >= beta && beta < INFINITY {
+                beta = (score + delta).min(INFINITY);
+            } else {
+                return (score, best_move);
+            }
+            delta *= 2;
         }
     }
 
+    fn publish_nodes(&mut self) {
+        self.signals.nodes.fetch_add(self.pos_counter - self.published_nodes, Ordering::Relaxed);
+        self.published_nodes = self.pos_counter;
+    }
 
-    fn sort_moves(&mut self, board: &Board, move_gen: &mut MoveGen, pv_move: Option<ChessMove>) -> Vec<ChessMove> {
-        let mut captures = Vec::new();
-        let mut res_val = Vec::new();
-        let color_captures = if board.side_to_move() == White {
-            Black
-        } else {
-            White
-        };
+    fn total_nodes(&self) -> u64 {
+        self.signals.nodes.load(Ordering::Relaxed) + self.pos_counter - self.published_nodes
+    }
+
+    /// Mate scores are stored relative to the position itself instead of the root, so they stay
+    /// correct when the position is reached again at a different ply.
+    fn store_transposition(&mut self, hash: u64, mut entry: TranspositionEntry, ply: usize) {
+        entry.score = score_to_tt(entry.score, ply);
+        self.transposition_table.store(hash, entry);
+    }
+
+    fn probe_transposition(&self, hash: u64, ply: usize) -> Option<TranspositionEntry> {
+        let mut entry = self.transposition_table.probe(hash)?;
+        entry.score = score_from_tt(entry.score, ply);
+        Some(entry)
+    }
+
+    /// Makes the moves stored for `position` and the positions after it the principal variation at
+    /// `ply`, at most `depth` of them.
+    fn pv_from_transposition_table(&mut self, mut position: Position, ply: usize, depth: u16) {
+        for i in ply..(ply + depth as usize).min(MAX_PLY) {
+            let Some(mov) = self.probe_transposition(position.board.get_hash(), i).and_then(|entry| entry.mov) else {
+                break;
+            };
+            // Only 16 bits of the hash are compared, so the move may belong to another position.
+            if !position.board.legal(mov) {
+                break;
+            }
+            self.pv_table[ply][i] = mov;
+            self.pv_length[ply] = i + 1;
+            position = position.make_move_new(mov);
+        }
+    }
 
-        if pv_move != None {
-            move_gen.remove_move(pv_move.unwrap());
-            res_val.push(pv_move.unwrap());
+    /// Makes `mov` followed by the line found one ply deeper the principal variation at `ply`.
+    fn update_pv(&mut self, ply: usize, mov: ChessMove) {
+        self.pv_table[ply][ply] = mov;
+        for i in ply + 1..self.pv_length[ply + 1] {
+            self.pv_table[ply][i] = self.pv_table[ply + 1][i];
         }
+        self.pv_length[ply] = self.pv_length[ply + 1].max(ply + 1);
+    }
 
-        move_gen.set_iterator_mask(*board.color_combined(color_captures));
-        move_gen.collect_into(&mut captures);
 
-        captures.sort_by(|mov1, mov2| {
-            (PIECE_VALUES[board.piece_on(mov1.get_dest()).unwrap().to_index()]
-                - PIECE_VALUES[board.piece_on(mov1.get_source()).unwrap().to_index()])
-                .cmp(
-                    &(PIECE_VALUES[board.piece_on(mov2.get_dest()).unwrap().to_index()]
-                        - PIECE_VALUES[board.piece_on(mov2.get_source()).unwrap().to_index()])
-                )
-        });
+    fn move_picker<'a>(&self, board: &'a Board, move_gen: MoveGen, tt_move: Option<ChessMove>, ply: usize) -> MovePicker<'a> {
+        let countermove = self.previous_move(ply, 1).and_then(|previous| self.countermoves[previous]);
+        MovePicker::new(board, move_gen, tt_move, self.killers[ply], countermove)
+    }
+
+    /// What the quiet moves are ordered by, the butterfly and continuation histories together.
+    fn quiet_score(&self, board: &Board, mov: ChessMove, ply: usize) -> i32 {
+        self.history_score(board, mov) + self.continuation_score(board, mov, ply)
+    }
+
+    /// Records `hash` as the position at `ply` of the search path, dropping whatever a previously
+    /// searched sibling left behind.
+    fn push_position(&mut self, hash: u64, ply: usize) {
+        self.position_history.truncate(self.game_length + ply);
+        self.position_history.push(hash);
+    }
+
+    /// Whether the position at `ply` already occurred in the game or earlier on the search path.
+    /// Only positions with the same side to move can be equal, so every second one is compared, and
+    /// nothing before the last capture or pawn move can come back. Neither can the positions up to and
+    /// including the one after a null move, which is not a move of the game.
+    fn is_repetition(&self, position: &Position, ply: usize) -> bool {
+        let path = &self.position_history[..self.game_length + ply];
+        let hash = position.board.get_hash();
+        let reversible_plies = position.halfmove_clock.min(position.plies_since_null_move.saturating_sub(1));
+        path.iter().rev().skip(1).step_by(2)
+            .take(reversible_plies as usize / 2)
+            .any(|&previous| previous == hash)
+    }
+
+    fn history_score(&self, board: &Board, mov: ChessMove) -> i32 {
+        self.history_scores[board.side_to_move().to_index()][mov.get_source().to_index()][mov.get_dest().to_index()]
+    }
+
+    /// Sum of the continuation histories of `mov` after the moves one and two plies earlier.
+    fn continuation_score(&self, board: &Board, mov: ChessMove, ply: usize) -> i32 {
+        let current = piece_square(board, mov);
+        (1..=CONTINUATION_PLIES)
+            .filter_map(|plies| self.previous_move(ply, plies).map(|previous| continuation_index(plies, previous, current)))
+            .map(|index| self.continuation_history[index])
+            .sum()
+    }
+
+    /// Piece and destination of the move made `plies` before reaching `ply`.
+    fn previous_move(&self, ply: usize, plies: usize) -> Option<usize> {
+        self.move_stack[ply.checked_sub(plies)?]
+    }
+
+    fn is_killer(&self, ply: usize, mov: ChessMove) -> bool {
+        self.killers[ply].contains(&Some(mov))
+    }
 
-        captures.reverse();
+    /// Quiet moves that neither come out of nor give check may be searched with reduced depth. Killers
+    /// refuted a sibling position, so they are searched in full like the moves ordered before them.
+    fn is_reducible(&self, board: &Board, child: &Board, mov: ChessMove, ply: usize) -> bool {
+        self.pruning && is_quiet(board, mov) && *board.checkers() == EMPTY && *child.checkers() == EMPTY && !self.is_killer(ply, mov)
+    }
+
+    /// Remembers a quiet move that caused a beta cutoff, deeper searches count for more.
+    fn update_quiet_heuristics(&mut self, board: &Board, mov: ChessMove, depth: u16, ply: usize) {
+        if !self.is_killer(ply, mov) {
+            self.killers[ply] = [Some(mov), self.killers[ply][0]];
+        }
+
+        let bonus = (depth as i32 * depth as i32).min(HISTORY_MAX);
+        update_history(&mut self.history_scores[board.side_to_move().to_index()][mov.get_source().to_index()][mov.get_dest().to_index()], bonus);
+
+        let current = piece_square(board, mov);
+        if let Some(previous) = self.previous_move(ply, 1) {
+            self.countermoves[previous] = Some(mov);
+        }
+        for plies in 1..=CONTINUATION_PLIES {
+            if let Some(previous) = self.previous_move(ply, plies) {
+                update_history(&mut self.continuation_history[continuation_index(plies, previous, current)], bonus);
+            }
+        }
+    }
 
-        move_gen.set_iterator_mask(!EMPTY);
-        let mut rest_moves = Vec::new();
-        move_gen.collect_into(&mut rest_moves);
+    fn report_current_move(&self, depth: u16, mov: ChessMove, number: usize) {
+        if self.start.elapsed() < CURRMOVE_DELAY {
+            return;
+        }
+        if let Some(reports) = &self.reports {
+            let _ = reports.send(SearchReport::CurrentMove { depth, mov, number });
+        }
+    }
 
-        res_val.append(&mut captures);
-        res_val.append(&mut rest_moves);
+    /// After "ponderhit" the search goes on as a normal timed search, with the clock starting now.
+    fn poll_ponderhit(&mut self) {
+        if self.pondering && !self.signals.ponder.load(Ordering::Relaxed) {
+            self.pondering = false;
+            if let Some(time) = &mut self.time {
+                time.restart();
+            }
+        }
+    }
 
-        return res_val;
+    #[inline]
+    fn is_stopped(&mut self) -> bool {
+        // Asking for the time or updating the shared node count on every node is too expensive.
+        if self.pos_counter & 1023 == 0 {
+            self.publish_nodes();
+            self.poll_ponderhit();
+            if !self.pondering && self.time.is_some_and(|time| time.hard_limit_reached()) {
+                self.signals.stop.store(true, Ordering::Relaxed);
+            }
+        }
+        if self.total_nodes() >= self.node_limit {
+            self.signals.stop.store(true, Ordering::Relaxed);
+        }
+        // The main thread always finishes its first iteration, so there is a move to fall back to.
+        if self.thread_index == 0 && self.completed_depth == 0 {
+            return false;
+        }
+        self.signals.stop.load(Ordering::Relaxed)
     }
 
 
-    fn pvs(&mut self, board: Board, mut alpha: i32, beta: i32, depth: u16) -> (i32, ChessMove) {
+    /// `root_moves` restricts which moves are searched at this node, it is only non-empty at the root.
+    fn pvs(&mut self, position: Position, mut alpha: i32, beta: i32, depth: u16, ply: usize, root_moves: &[ChessMove]) -> (i32, ChessMove) {
+        self.pv_length[ply] = ply;
+        let board = position.board;
+
+        if self.is_stopped() {
+            return (0, ChessMove::default());
+        }
+
         if depth == 0 {
-            return (self.quiesce_search(alpha, beta, &board), ChessMove::default());
+            // Every stored bound comes from a search at least as deep as the quiescence search.
+            let stored = self.probe_transposition(board.get_hash(), ply).and_then(|entry| entry.cutoff(alpha, beta, depth));
+            if let Some(score) = stored.filter(|_| ply > 0 && self.transposition_cutoffs) {
+                return (score, ChessMove::default());
+            }
+            return (self.quiesce_search(alpha, beta, &position, ply), ChessMove::default());
         }
 
+        // Repeating a position cannot be better than a draw, the opponent can always go for the repetition.
+        if ply > 0 && self.is_repetition(&position, ply) {
+            return (0, ChessMove::default());
+        }
+        self.push_position(board.get_hash(), ply);
+
+        self.seldepth = self.seldepth.max(ply as u16);
 
-        let mut pvsearch = true;
+
+        let mut found_pv = false;
 
         // Check for checkmate
         let mut legal_moves = MoveGen::new_legal(&board);
@@ -337,113 +852,254 @@ impl Engine {
                 return if board.checkers().0 == 0 {
                     (0, ChessMove::default())
                 } else {
-                    (-10_000_000, ChessMove::default())
+                    (-MATE_SCORE + ply as i32, ChessMove::default())
                 };
             }
             _ => {}
         }
-        //let r_table_entry = self.repeat_table.get(board.get_hash()).or(Some(0));
-//
-        //if r_table_entry == Some(2u16) {
-        //    let tmp = 10 * if board.side_to_move() == White {
-        //        -1
-        //    } else {
-        //        1
-        //    };
-        //    return (tmp, ChessMove::default());
-        //}
-//
-        //self.repeat_table.add(board.get_hash(), r_table_entry.or(Some(0)).unwrap() + 1);
-
-
-        let entry = self.transposition_table.get(board.get_hash());
-        let mut pv_move = None;
-        if entry.is_some() && entry.unwrap().mov.is_some() {
-            pv_move = entry.unwrap().mov
-        }
-
-        let moves = self.sort_moves(&board, &mut legal_moves, pv_move);
-        let mut moves_iter = moves.iter();
-        let mut score = i32::MIN;
+        // Checked after checkmate, which still counts on the hundredth half move.
+        if ply > 0 && position.halfmove_clock >= FIFTY_MOVE_LIMIT {
+            return (0, ChessMove::default());
+        }
+
+        let entry = self.probe_transposition(board.get_hash(), ply);
+        // The root always searches, it has to come up with a move and a PV.
+        if ply > 0 && self.transposition_cutoffs {
+            if let Some(score) = entry.and_then(|entry| entry.cutoff(alpha, beta, depth)) {
+                // An exact score inside the window continues the PV, which the table still holds.
+                if score > alpha && score < beta {
+                    self.pv_from_transposition_table(position, ply, depth);
+                }
+                return (score, ChessMove::default());
+            }
+        }
+        let mut pv_move = entry.and_then(|entry| entry.mov);
+
+        if !root_moves.is_empty() {
+            restrict_moves(&board, &mut legal_moves, root_moves);
+            pv_move = pv_move.filter(|mov| root_moves.contains(mov));
+        }
+
+        let mut moves = self.move_picker(&board, legal_moves, pv_move, ply);
+        let mut score;
         let mut b_mov = ChessMove::default();
+        let mut move_count = 0;
+
+        while let Some(mov) = moves.next(|mov| self.quiet_score(&board, mov, ply)) {
+            // MoveGen can only drop whole destination squares, this catches the unwanted under-promotions.
+            if !root_moves.is_empty() && !root_moves.contains(&mov) {
+                continue;
+            }
+            let index = move_count;
+            move_count += 1;
+
+            if ply == 0 {
+                self.report_current_move(depth, mov, index + 1);
+            }
 
-        for mov in moves_iter {
+            let child = position.make_move_new(mov);
+            self.move_stack[ply] = Some(piece_square(&board, mov));
             self.pos_counter += 1;
-            if pvsearch {
-                (score, _) = self.pvs(board.make_move_new(*mov), beta.saturating_neg(), alpha.saturating_neg(), depth - 1);
-                score = score.saturating_neg();
+            // Only the first move gets the full window, after a fail low at the root the others would
+            // otherwise all be searched as PV moves.
+            if index == 0 {
+                (score, _) = self.pvs(child, -beta, -alpha, depth - 1, ply + 1, &[]);
+                score = -score;
             } else {
-                (score) = self.zws(board.make_move_new(*mov), alpha.saturating_neg(), depth - 1);
-                score = score.saturating_neg();
+                let reduction = if self.is_reducible(&board, &child.board, mov, ply) {
+                    late_move_reduction(depth, index, true, self.history_score(&board, mov))
+                } else {
+                    0
+                };
+                (score) = self.zws(child, -alpha, depth - 1 - reduction, ply + 1, true);
+                score = -score;
+                // A reduced move that beats alpha has to prove it at full depth.
+                if score > alpha && reduction > 0 {
+                    score = -self.zws(child, -alpha, depth - 1, ply + 1, true);
+                }
                 if score > alpha {
-                    (score, _) = self.pvs(board.make_move_new(*mov), beta.saturating_neg(), alpha.saturating_neg(), depth - 1);
-                    score = score.saturating_neg();
+                    (score, _) = self.pvs(child, -beta, -alpha, depth - 1, ply + 1, &[]);
+                    score = -score;
                 }
             }
 
-            //if depth == 5 {
-            //    println!("beginning");
-            //}
+            if self.is_stopped() {
+                return (0, ChessMove::default());
+            }
 
             if score >= beta {
-                self.transposition_table.add(board.get_hash(), TranspositionEntry {
-                    mov: Some(*mov),
-                    score: Some(beta),
-                    node_type: NodeType::CutNode,
-                    depth,
-                });
-                return (beta, *mov); // fail-hard-beta
+                if is_quiet(&board, mov) {
+                    self.update_quiet_heuristics(&board, mov, depth, ply);
+                }
+                if root_moves.is_empty() {
+                    self.store_transposition(board.get_hash(), TranspositionEntry {
+                        mov: Some(mov),
+                        score: beta,
+                        node_type: NodeType::CutNode,
+                        depth,
+                    }, ply);
+                }
+                return (beta, mov); // fail-hard-beta
             }
 
             if score > alpha {
                 alpha = score;
-                pvsearch = false;
-                self.transposition_table.add(board.get_hash(), TranspositionEntry {
-                    mov: Some(*mov),
-                    score: Some(beta),
-                    node_type: NodeType::PVNode,
-                    depth,
-                });
-                self.pv_table[(depth - 1) as usize] = *mov;
-                b_mov = *mov;
+                found_pv = true;
+                self.update_pv(ply, mov);
+                b_mov = mov;
             }
         }
 
-        return (alpha, b_mov);
+        // A root searched with only some of its moves says nothing about the position as a whole.
+        if root_moves.is_empty() {
+            // Without a move above alpha the score is only an upper bound, the old move is kept for ordering.
+            self.store_transposition(board.get_hash(), TranspositionEntry {
+                mov: if found_pv { Some(b_mov) } else { pv_move },
+                score: alpha,
+                node_type: if found_pv { NodeType::PVNode } else { NodeType::AllNode },
+                depth,
+            }, ply);
+        }
+
+        (alpha, b_mov)
     }
 
-    fn zws(&mut self, board: Board, beta: i32, depth: u16) -> i32 {
+    /// `null_move` allows null-move pruning at this node, it is off right after a null move.
+    /// The nodes searched here are not on the PV, so unlike `pvs` this is where pruning happens.
+    fn zws(&mut self, position: Position, beta: i32, depth: u16, ply: usize, null_move: bool) -> i32 {
+        let board = position.board;
+        if self.is_stopped() {
+            return 0;
+        }
+
         if depth == 0 {
-            return self.quiesce_search(beta - 1, beta, &board);
+            let stored = self.probe_transposition(board.get_hash(), ply).and_then(|entry| entry.cutoff(beta - 1, beta, depth));
+            if let Some(score) = stored.filter(|_| self.transposition_cutoffs) {
+                return score;
+            }
+            return self.quiesce_search(beta - 1, beta, &position, ply);
         }
 
+        if self.is_repetition(&position, ply) {
+            return 0;
+        }
+        self.push_position(board.get_hash(), ply);
+
+        self.seldepth = self.seldepth.max(ply as u16);
+
         // Check for checkmate
-        let mut legal_moves = MoveGen::new_legal(&board);
+        let legal_moves = MoveGen::new_legal(&board);
         match legal_moves.len() {
             0 => {
                 return if board.checkers().0 == 0 {
                     0
                 } else {
-                    -10_000_000
+                    -MATE_SCORE + ply as i32
                 };
             }
             _ => {} // else do nothing
         }
+        if position.halfmove_clock >= FIFTY_MOVE_LIMIT {
+            return 0;
+        }
 
-        let sorted_moves = self.sort_moves(&board, &mut legal_moves, None);
+        let entry = self.probe_transposition(board.get_hash(), ply);
+        if let Some(score) = entry.and_then(|entry| entry.cutoff(beta - 1, beta, depth)).filter(|_| self.transposition_cutoffs) {
+            return score;
+        }
+        let pv_move = entry.and_then(|entry| entry.mov);
+
+        // If passing still keeps the score above beta, a real move will do so too. That fails in
+        // zugzwang, which mostly comes up when there are only pawns left to move.
+        if self.pruning && null_move && depth >= NULL_MOVE_MIN_DEPTH && beta.abs() < MATE_BOUND && has_pieces(&board) {
+            let eval = evaluate_position(&position);
+            if let Some(null_position) = position.make_null_move().filter(|_| eval >= beta) {
+                let margin_plies = ((eval - beta) / NULL_MOVE_MARGIN_STEP).min(NULL_MOVE_MAX_MARGIN_PLIES) as u16;
+                let reduction = NULL_MOVE_REDUCTION + depth / NULL_MOVE_DEPTH_STEP + margin_plies;
+
+                self.move_stack[ply] = None;
+                self.pos_counter += 1;
+                let score = -self.zws(null_position, 1 - beta, depth.saturating_sub(reduction + 1), ply + 1, false);
+                if self.is_stopped() {
+                    return 0;
+                }
+                if score >= beta {
+                    if depth < NULL_MOVE_VERIFICATION_DEPTH {
+                        return beta;
+                    }
+                    if self.zws(position, beta, depth - reduction, ply, false) >= beta {
+                        return beta;
+                    }
+                    if self.is_stopped() {
+                        return 0;
+                    }
+                }
+            }
+        }
 
-        for mov in legal_moves {
-            let score = self.zws(board.make_move_new(mov), 1 - beta, depth - 1).saturating_neg();
+        let mut moves = self.move_picker(&board, legal_moves, pv_move, ply);
+        let mut move_count = 0;
+
+        while let Some(mov) = moves.next(|mov| self.quiet_score(&board, mov, ply)) {
+            let index = move_count;
+            move_count += 1;
+            let child = position.make_move_new(mov);
+            self.move_stack[ply] = Some(piece_square(&board, mov));
+            let reduction = if self.is_reducible(&board, &child.board, mov, ply) {
+                late_move_reduction(depth, index, false, self.history_score(&board, mov))
+            } else {
+                0
+            };
+
+            self.pos_counter += 1;
+            let mut score = -self.zws(child, 1 - beta, depth - 1 - reduction, ply + 1, true);
+            if score >= beta && reduction > 0 {
+                score = -self.zws(child, 1 - beta, depth - 1, ply + 1, true);
+            }
+            if self.is_stopped() {
+                return 0;
+            }
             if score >= beta {
+                if is_quiet(&board, mov) {
+                    self.update_quiet_heuristics(&board, mov, depth, ply);
+                }
+                self.store_transposition(board.get_hash(), TranspositionEntry {
+                    mov: Some(mov),
+                    score: beta,
+                    node_type: NodeType::CutNode,
+                    depth,
+                }, ply);
                 return beta;
             }
         }
 
-        return beta - 1;
+        self.store_transposition(board.get_hash(), TranspositionEntry {
+            mov: pv_move,
+            score: beta - 1,
+            node_type: NodeType::AllNode,
+            depth,
+        }, ply);
+
+        beta - 1
     }
 
-    fn quiesce_search(&mut self, mut alpha: i32, beta: i32, board: &Board) -> i32 {
-        let standing_pat = evaluate(board);
+    fn quiesce_search(&mut self, mut alpha: i32, beta: i32, position: &Position, ply: usize) -> i32 {
+        let board = &position.board;
+        if self.is_stopped() {
+            return 0;
+        }
+
+        if ply > 0 && self.is_repetition(position, ply) {
+            return 0;
+        }
+        self.push_position(board.get_hash(), ply);
+
+        self.seldepth = self.seldepth.max(ply as u16);
+
+        let standing_pat = evaluate_position(position);
+        if ply >= MAX_PLY - 1 {
+            return standing_pat;
+        }
         if standing_pat >= beta {
             return beta;
         }
@@ -452,7 +1108,6 @@ impl Engine {
         }
 
         let c_moves = get_capture_moves(board);
-        let mut score = 0;
 
         if c_moves.len() == 0 {
             return standing_pat;
@@ -460,62 +1115,106 @@ impl Engine {
 
         for mov in c_moves {
             self.pos_counter += 1;
-            score = self.quiesce_search(beta.saturating_neg(), alpha.saturating_neg(), &board.make_move_new(mov)).saturating_neg();
+            let score = -self.quiesce_search(-beta, -alpha, &position.make_move_new(mov), ply + 1);
+            if self.is_stopped() {
+                return 0;
+            }
 
             if score >= beta {
                 return beta;
             }
-            if score < alpha {
+            if score > alpha {
                 alpha = score
             }
         }
 
-        return alpha;
+        alpha
+    }
+}
+
+/// Converts a score into the number of moves until mate, negative if the side to move gets mated.
+pub fn mate_in(score: i32) -> Option<i32> {
+    if score >= MATE_BOUND {
+        return Some((MATE_SCORE - score + 1) / 2);
     }
+    if score <= -MATE_BOUND {
+        return Some(-(MATE_SCORE + score) / 2);
+    }
+    None
 }
 
-struct SortedMoveGen {
-    move_gen: MoveGen,
-    total_size: usize,
-    iterated_size: usize,
+fn evaluate_position(position: &Position) -> i32 {
+    scale_by_halfmove_clock(evaluate(&position.board), position.halfmove_clock)
 }
 
-impl SortedMoveGen {
-    fn new(move_gen: MoveGen, board: &Board) -> SortedMoveGen {
-        let len = move_gen.len();
+/// Whether the side to move has anything besides king and pawns.
+fn has_pieces(board: &Board) -> bool {
+    let pawns_and_king = board.pieces(Piece::Pawn) | board.pieces(Piece::King);
+    board.color_combined(board.side_to_move()) & !pawns_and_king != EMPTY
+}
 
-        let mut s_mov_gen = SortedMoveGen {
-            move_gen,
-            total_size: len,
-            iterated_size: 0,
-        };
+/// Index of the moving piece, with its colour, and the destination of `mov` into the countermove and
+/// continuation tables.
+fn piece_square(board: &Board, mov: ChessMove) -> usize {
+    let piece = board.piece_on(mov.get_source()).unwrap();
+    (board.side_to_move().to_index() * 6 + piece.to_index()) * 64 + mov.get_dest().to_index()
+}
 
-        let color_captures = if board.side_to_move() == White {
-            Black
-        } else {
-            White
-        };
+fn continuation_index(plies: usize, previous: usize, current: usize) -> usize {
+    ((plies - 1) * PIECE_SQUARES + previous) * PIECE_SQUARES + current
+}
 
-        s_mov_gen.move_gen.set_iterator_mask(*board.color_combined(color_captures));
+/// Moves `score` by `bonus`, less so the closer it already is to +-HISTORY_MAX.
+fn update_history(score: &mut i32, bonus: i32) {
+    *score += bonus - *score * bonus / HISTORY_MAX;
+}
 
-        return s_mov_gen;
+/// How many plies less than `depth - 1` the move at `index` of the move order is searched with.
+/// Moves at PV nodes and moves with a good history are reduced less, the reduced search always
+/// keeps at least one ply.
+fn late_move_reduction(depth: u16, index: usize, pv_node: bool, history: i32) -> u16 {
+    if depth < LMR_MIN_DEPTH || index < LMR_MIN_MOVES {
+        return 0;
     }
+    let reduction = (LMR_BASE + (depth as f64).ln() * (index as f64).ln() / LMR_DIVISOR) as i32
+        - pv_node as i32
+        - history / LMR_HISTORY_DIVISOR;
+    (reduction.max(0) as u16).min(depth - 2)
 }
 
-impl Iterator for SortedMoveGen {
-    type Item = ChessMove;
-    fn next(&mut self) -> Option<ChessMove> {
-        let mut tmp_move = self.move_gen.next();
+/// Pulls the evaluation towards a draw as the fifty-move rule comes closer, so that captures and
+/// pawn moves, which reset the clock, look better in won endings than shuffling around.
+fn scale_by_halfmove_clock(score: i32, halfmove_clock: u16) -> i32 {
+    let clock = halfmove_clock.min(FIFTY_MOVE_LIMIT) as i32;
+    score * (2 * FIFTY_MOVE_LIMIT as i32 - clock) / (2 * FIFTY_MOVE_LIMIT as i32)
+}
 
-        // Set new values for iterating
-        if self.iterated_size < self.total_size && tmp_move == None {
-            self.move_gen.set_iterator_mask(!EMPTY);
-            tmp_move = self.move_gen.next();
-        }
+fn score_to_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND {
+        return score + ply as i32;
+    }
+    if score <= -MATE_BOUND {
+        return score - ply as i32;
+    }
+    score
+}
 
-        self.iterated_size += 1;
+fn score_from_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND {
+        return score - ply as i32;
+    }
+    if score <= -MATE_BOUND {
+        return score + ply as i32;
+    }
+    score
+}
 
-        return tmp_move;
+/// Removes every move from `move_gen` that does not go from and to the same squares as one of `allowed`.
+fn restrict_moves(board: &Board, move_gen: &mut MoveGen, allowed: &[ChessMove]) {
+    for mov in MoveGen::new_legal(board) {
+        if !allowed.iter().any(|m| m.get_source() == mov.get_source() && m.get_dest() == mov.get_dest()) {
+            move_gen.remove_move(mov);
+        }
     }
 }
 
@@ -542,7 +1241,189 @@ fn get_capture_moves(board: &Board) -> Vec<ChessMove> {
 
     ret_val.reverse();
 
-    return ret_val;
+    ret_val
 }
 
 
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::time_manager::TimeControl;
+
+    /// Searches `fen` to `depth` and returns the score, best move and node count of the last iteration.
+    fn search(engine: &mut Engine, fen: &str, depth: u16) -> (i32, ChessMove, u64) {
+        search_with_limits(engine, fen, SearchLimits { depth: Some(depth), ..SearchLimits::default() })
+    }
+
+    fn search_with_limits(engine: &mut Engine, fen: &str, limits: SearchLimits) -> (i32, ChessMove, u64) {
+        let (tx, rx) = mpsc::channel();
+        let position = Position::from_fen(fen).unwrap();
+        engine.iterative_deepening(position, Vec::new(), tx, limits, Vec::new(), 1, Arc::new(SearchSignals::default()));
+
+        let info = rx.try_iter()
+            .filter_map(|report| match report {
+                SearchReport::Iteration(info) => Some(info),
+                SearchReport::CurrentMove { .. } => None,
+            })
+            .last()
+            .unwrap();
+        (info.score, info.pv[0], info.nodes)
+    }
+
+    /// A second search of the same position finds the stored bounds and cuts off right below the root.
+    fn assert_second_search_cheaper(fen: &str, depth: u16) {
+        let mut engine = Engine::new(16);
+        let (first_score, first_move, first_nodes) = search(&mut engine, fen, depth);
+        let (second_score, second_move, second_nodes) = search(&mut engine, fen, depth);
+
+        assert_eq!(first_move, second_move, "{fen}");
+        assert_eq!(first_score, second_score, "{fen}");
+        assert!(second_nodes * 10 < first_nodes, "{fen}: {second_nodes} nodes after {first_nodes}");
+    }
+
+    #[test]
+    fn transposition_cutoffs_start_position() {
+        assert_second_search_cheaper("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 5);
+    }
+
+    #[test]
+    fn transposition_cutoffs_middlegame() {
+        assert_second_search_cheaper("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", 4);
+    }
+
+    #[test]
+    fn transposition_cutoffs_with_reductions() {
+        // Deep enough for late move reductions to shape the tree that is stored.
+        assert_second_search_cheaper("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", 6);
+    }
+
+    #[test]
+    fn transposition_cutoffs_save_nodes() {
+        // Deep enough for many transpositions, the savings are small in shallow searches.
+        let (fen, depth) = ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 8);
+        let mut without_cutoffs = Engine::new(16);
+        without_cutoffs.transposition_cutoffs = false;
+        let (score, best_move, nodes) = search(&mut without_cutoffs, fen, depth);
+        let (cutoff_score, cutoff_move, cutoff_nodes) = search(&mut Engine::new(16), fen, depth);
+
+        assert_eq!(best_move, cutoff_move);
+        assert_eq!(score, cutoff_score);
+        assert!(cutoff_nodes * 3 < nodes * 2, "{cutoff_nodes} nodes with cutoffs, {nodes} without");
+    }
+
+    #[test]
+    fn quiescence_takes_winning_captures_only() {
+        // The queen takes a hanging rook.
+        let position = Position::from_fen("4k3/8/8/3r4/8/8/3Q4/4K3 w - - 0 1").unwrap();
+        let score = Engine::new(1).quiesce_search(-INFINITY, INFINITY, &position, 0);
+        assert!(score > evaluate_position(&position) + ROOK_VALUE / 2, "{score}");
+
+        // Taking the defended pawn loses the queen, so standing pat is best.
+        let position = Position::from_fen("4k3/8/2p5/3p4/8/8/3Q4/4K3 w - - 0 1").unwrap();
+        let score = Engine::new(1).quiesce_search(-INFINITY, INFINITY, &position, 0);
+        assert_eq!(score, evaluate_position(&position));
+    }
+
+    #[test]
+    fn transposition_cutoffs_keep_mate_scores() {
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5Q2/PPPP1PPP/RNB1K1NR w KQkq - 2 4";
+        assert_second_search_cheaper(fen, 4);
+
+        let (score, best_move, _) = search(&mut Engine::new(16), fen, 4);
+        assert_eq!(best_move, ChessMove::from_str("f3f7").unwrap());
+        assert_eq!(mate_in(score), Some(1));
+    }
+
+    #[test]
+    fn go_mate_finds_the_mate() {
+        let cases = [
+            ("2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - 0 1", 2, "g3g6"),
+            ("r1b1kb1r/pppp1ppp/5q2/4n3/3KP3/2N3PN/PPP4P/R1BQ1B1R b kq - 0 1", 3, "f8c5"),
+        ];
+        for (fen, mate, mate_move) in cases {
+            let limits = SearchLimits { mate: Some(mate), ..SearchLimits::default() };
+            let (score, best_move, _) = search_with_limits(&mut Engine::new(16), fen, limits);
+            assert_eq!(mate_in(score), Some(mate as i32), "{fen}");
+            assert_eq!(best_move, ChessMove::from_str(mate_move).unwrap(), "{fen}");
+        }
+    }
+
+    #[test]
+    fn go_mate_with_a_large_count() {
+        let mut engine = Engine::new(16);
+        let limits = SearchLimits { mate: Some(u16::MAX), depth: Some(3), ..SearchLimits::default() };
+        search_with_limits(&mut engine, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", limits);
+        assert_eq!(engine.completed_depth, 3);
+    }
+
+    /// Plays `moves` from `fen` along the search path, "null" for a null move, and tells whether
+    /// the final position counts as a repetition.
+    fn repeats(fen: &str, moves: &[&str]) -> bool {
+        let mut engine = Engine::new(1);
+        let mut position = Position::from_fen(fen).unwrap();
+        for (ply, &mov) in moves.iter().enumerate() {
+            engine.push_position(position.board.get_hash(), ply);
+            position = match mov {
+                "null" => position.make_null_move().unwrap(),
+                mov => position.make_move_new(ChessMove::from_str(mov).unwrap()),
+            };
+        }
+        engine.is_repetition(&position, moves.len())
+    }
+
+    #[test]
+    fn repetitions_stop_at_null_moves() {
+        let fen = "4k3/8/8/8/8/8/8/R3K3 w - - 0 1";
+        assert!(repeats(fen, &["a1a2", "e8d8", "a2a1", "d8e8"]));
+        // Back to the position after the null move, which never came up in the game.
+        assert!(!repeats(fen, &["null", "e8d8", "a1a2", "d8e8", "a2a1"]));
+        // Back to the position before the null move, the king walks around a triangle to make up for it.
+        assert!(!repeats(fen, &["null", "e8d8", "a1a2", "d8d7", "a2a1", "d7e8"]));
+        assert!(repeats(fen, &["null", "e8d8", "a1a2", "d8e8", "a2a1", "e8d8", "a1a2"]));
+    }
+
+    #[test]
+    fn timed_search_of_a_single_search_move() {
+        let mut engine = Engine::new(16);
+        let position = Position::default();
+        let time_control = TimeControl { move_time: Some(10_000), ..TimeControl::default() };
+        let time = TimeManager::new(&time_control, &position.board, Duration::ZERO);
+        let limits = SearchLimits { depth: Some(4), time: Some(time), ..SearchLimits::default() };
+        let search_moves = vec![ChessMove::from_str("e2e4").unwrap()];
+        engine.iterative_deepening(position, Vec::new(), mpsc::channel().0, limits, search_moves, 1, Arc::new(SearchSignals::default()));
+
+        assert_eq!(engine.completed_depth, 4);
+    }
+
+    #[test]
+    fn restricted_root_is_not_stored() {
+        let mut engine = Engine::new(16);
+        let position = Position::default();
+        let limits = SearchLimits { depth: Some(4), ..SearchLimits::default() };
+        let search_moves = vec![ChessMove::from_str("a2a3").unwrap()];
+        engine.iterative_deepening(position, Vec::new(), mpsc::channel().0, limits, search_moves, 1, Arc::new(SearchSignals::default()));
+
+        assert_eq!(engine.transposition_table.probe(position.board.get_hash()), None);
+    }
+
+    #[test]
+    fn reductions_spare_checks_and_killers() {
+        let mut engine = Engine::new(1);
+        let reducible = |engine: &Engine, fen: &str, mov: &str| {
+            let board = Board::from_str(fen).unwrap();
+            let mov = ChessMove::from_str(mov).unwrap();
+            engine.is_reducible(&board, &board.make_move_new(mov), mov, 0)
+        };
+
+        let fen = "4k3/8/8/8/8/8/8/R3K3 w - - 0 1";
+        assert!(reducible(&engine, fen, "a1a2"));
+        assert!(!reducible(&engine, fen, "a1a8"), "a move giving check");
+        assert!(!reducible(&engine, "4k3/8/8/8/8/8/4r3/R3K3 w - - 0 1", "e1d1"), "a move out of check");
+
+        engine.killers[0][1] = Some(ChessMove::from_str("a1a2").unwrap());
+        assert!(!reducible(&engine, fen, "a1a2"), "a killer move");
+    }
+}
@@ -2,57 +2,85 @@
 
 use std::io::{self, BufRead};
 
-use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use chess::{Board, ChessMove};
-use chess::Color::White;
+use chess::ChessMove;
+
+use crate::Engine::{SearchReport, SearchSignals};
+use crate::options::Options;
+use crate::position::Position;
+use crate::time_manager::TimeManager;
+use crate::uci::{Command, GoCommand};
 
 mod Engine;
+mod move_picker;
+mod options;
+mod position;
+mod time_manager;
+mod transposition_table;
+mod uci;
+
+// How often the "go" loop wakes up to collect search results while waiting for GUI commands.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// Used for a plain "go" without any clock or other limit.
+const DEFAULT_MOVE_TIME: u64 = 10_000;
 
 struct UciHandler {
-    chess_board: Board,
-    time_white: f64,
-    time_black: f64,
-    moves_made: u16,
-    time_force: f64,
+    commands: Receiver<String>,
+    position: Position,
+    // Hashes of the positions played before `position`, for repetition detection.
+    game_history: Vec<u64>,
+    options: Options,
+    // Lives across searches to keep its transposition table, only handed to the search thread during "go".
+    engine: Option<Engine::Engine>,
 }
 
 impl UciHandler {
     fn new() -> Self {
         // Initialize your UCI handler here
+        let options = Options::new();
+        let engine = Engine::Engine::new(options.get(options::HASH) as usize);
         UciHandler {
-            chess_board: Board::default(),
-            time_white: 0.0,
-            time_black: 0.0,
-            moves_made: 0,
-            time_force: 0.0
+            commands: spawn_stdin_reader(),
+            position: Position::default(),
+            game_history: Vec::new(),
+            options,
+            engine: Some(engine),
         }
     }
 
     fn run(&mut self) {
-        let stdin = io::stdin();
-        let mut lines = stdin.lock().lines().map(|l| l.unwrap());
-
-        loop {
-            if let Some(command) = lines.next() {
-                self.handle_command(&command);
-            }
+        while let Ok(command) = self.commands.recv() {
+            self.handle_command(&command);
         }
     }
 
+    /// Malformed commands are reported with "info string error: ..." and change nothing.
     fn handle_command(&mut self, command: &str) {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        match parts[0] {
-            "uci" => self.uci(),
-            "isready" => self.isready(),
-            "ucinewgame" => {} // Do nothing on ucinewgame.
-            "position" => self.handle_position_command(parts),
-            "go" => self.handle_go_command(parts),
-            "quit" => std::process::exit(0),
-            _ => println!("Unknown command: {}", command),
+        let command = match uci::parse_command(command, &self.position.board) {
+            Ok(command) => command,
+            Err(err) => {
+                println!("info string error: {err}");
+                return;
+            }
+        };
+
+        match command {
+            Command::Uci => self.uci(),
+            Command::IsReady => self.isready(),
+            Command::UciNewGame => self.engine_mut().new_game(),
+            Command::Position { position, moves } => self.handle_position_command(position, moves),
+            Command::Go(go) => self.handle_go_command(go),
+            Command::SetOption { name, value } => self.handle_setoption_command(&name, value.as_deref()),
+            // Nothing is running that could be stopped.
+            Command::Stop | Command::PonderHit => {}
+            Command::Quit => std::process::exit(0),
         }
     }
 
@@ -60,6 +88,7 @@ impl UciHandler {
         // Print UCI identification information
         println!("id name KekChess");
         println!("id author Jonathan Heinz");
+        self.options.print();
         println!("uciok");
     }
 
@@ -67,111 +96,203 @@ impl UciHandler {
         println!("readyok");
     }
 
-    fn handle_position_command(&mut self, parts: Vec<&str>) {
-        if parts.contains(&"startpos") {
-            self.chess_board = Board::default()
+    fn handle_position_command(&mut self, position: Position, moves: Vec<ChessMove>) {
+        self.position = position;
+        self.game_history.clear();
+        for mov in moves {
+            self.game_history.push(self.position.board.get_hash());
+            self.position = self.position.make_move_new(mov);
         }
-        if parts.contains(&"fen") {
-            let pos_fen = parts.iter().position(|&s| s == "fen").unwrap();
-            let fen = *parts.get(pos_fen + 1).unwrap();
-            let fen = fen.to_owned() + " " + *parts.get(pos_fen + 2).unwrap();
-            let fen = fen + " " + *parts.get(pos_fen + 3).unwrap();
-            let fen = fen + " " + *parts.get(pos_fen + 4).unwrap();
-            let fen = fen + " " + *parts.get(pos_fen + 5).unwrap();
-            let fen = fen + " " + *parts.get(pos_fen + 5).unwrap();
-            self.chess_board = Board::from_str(&fen).unwrap();
-        }
-
-        if parts.contains(&"moves") {
-            self.moves_made = 0;
-            let pos_moves = parts.iter().position(|&s| s == "moves").unwrap();
-            for i in pos_moves + 1..parts.len() {
-                let uci_move = *parts.get(i).unwrap();
+    }
 
-                let parsed_move = chess::ChessMove::from_str(uci_move).unwrap();
-                self.chess_board = self.chess_board.make_move_new(parsed_move);
-                self.moves_made += 1;
+    fn handle_setoption_command(&mut self, name: &str, value: Option<&str>) {
+        match self.options.set(name, value) {
+            Ok(options::HASH) => {
+                let hash_mb = self.options.get(options::HASH) as usize;
+                self.engine_mut().resize_hash(hash_mb);
+            }
+            Ok(options::CLEAR_HASH) => self.engine_mut().clear_hash(),
+            Ok(options::THREADS) => {
+                let threads = self.options.get(options::THREADS) as usize;
+                self.engine_mut().set_threads(threads);
             }
+            Ok(_) => {}
+            Err(err) => println!("info string error: {err}"),
         }
     }
 
-    // Example method to handle the "go" command
-    fn handle_go_command(&mut self, parts: Vec<&str>) {
-        if parts.contains(&"btime") {
-            let pos_btime = parts.iter().position(|&s| s == "btime").unwrap();
-            let time = *parts.get(pos_btime + 1).unwrap();
-            self.time_black = time.parse().unwrap();
-            self.time_black /= 1000.0;
-        }
+    fn engine_mut(&mut self) -> &mut Engine::Engine {
+        self.engine.as_mut().expect("engine is only taken away while searching")
+    }
 
-        if parts.contains(&"wtime") {
-            let pos_wtime = parts.iter().position(|&s| s == "wtime").unwrap();
-            let time = *parts.get(pos_wtime + 1).unwrap();
-            self.time_white = time.parse().unwrap();
-            self.time_white /= 1000.0;
+    fn handle_go_command(&mut self, go: GoCommand) {
+        let infinite = go.infinite;
+        let mut pondering = go.ponder;
+        let mut time_control = go.time_control;
+        let mut limits = Engine::SearchLimits {
+            depth: go.depth,
+            nodes: go.nodes,
+            mate: go.mate,
+            time: None,
+        };
+
+        // "go infinite" and searches bounded only by depth, nodes or mate run until they finish or are stopped.
+        if time_control.is_empty() && !infinite && !limits.is_bounded() {
+            time_control.move_time = Some(DEFAULT_MOVE_TIME);
         }
-
-        if parts.contains(&"movetime") {
-            let pos_movtime = parts.iter().position(|&s| s == "movetime").unwrap();
-            let time = *parts.get(pos_movtime + 1).unwrap();
-            self.time_force = time.parse().unwrap();
+        if !time_control.is_empty() && !infinite {
+            let move_overhead = Duration::from_millis(self.options.get(options::MOVE_OVERHEAD) as u64);
+            limits.time = Some(TimeManager::new(&time_control, &self.position.board, move_overhead));
         }
 
-        let mut engine = Engine::Engine::new();
-        let mut time_slice = 10.0;
-
-        if self.chess_board.side_to_move() == White && self.time_white != 0.0 {
-            if self.moves_made != 60 {
-                time_slice = self.time_white / (60 - self.moves_made) as f64;
-            } else {
-                time_slice = self.time_white / (150 - self.moves_made) as f64;
-            }
-        } else if self.time_black != 0.0 {
-            if self.moves_made != 60 {
-                time_slice = self.time_black / (60 - self.moves_made) as f64;
-            } else {
-                time_slice = self.time_black / (150 - self.moves_made) as f64;
-            }
-        } else if self.time_force != 0.0 {
-            time_slice = self.time_force / 1000.0
-        }
+        let search_moves = go.search_moves;
+        let multi_pv = self.options.get(options::MULTI_PV) as usize;
+        let mut engine = self.engine.take().expect("engine is only taken away while searching");
 
         let (tx, rx) = mpsc::channel();
-        let (tx_cancle, rx_cancle) = mpsc::channel();
-        let board  = self.chess_board.clone();
-        let _ = thread::spawn(move || {
-            engine.iterative_deepening(board, tx, rx_cancle);
+        // "go ponder" searches the expected position without a time limit until "ponderhit".
+        let signals = Arc::new(SearchSignals::default());
+        signals.ponder.store(pondering, Ordering::Relaxed);
+        let search_signals = signals.clone();
+        let position = self.position;
+        let history = self.game_history.clone();
+        let search = thread::spawn(move || {
+            engine.iterative_deepening(position, history, tx, limits, search_moves, multi_pv, search_signals);
+            engine
         });
 
-        let mut score = 0;
-        let mut best_move = ChessMove::default();
-        let mut nodes = 0u32;
-        let mut depth = 0u16;
+        let mut result = None;
+        let mut pending = Vec::new();
+        let mut quit = false;
+        let mut searching = true;
+
+        loop {
+            if searching {
+                match rx.try_recv() {
+                    Ok(report) => {
+                        print_report(&report);
+                        if let SearchReport::Iteration(info) = report {
+                            if info.multi_pv == 1 {
+                                result = Some(info);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        searching = false;
+                        // In infinite and ponder mode the GUI still expects bestmove only after "stop" or "ponderhit".
+                        if !infinite && !pondering {
+                            break;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => {}
+                }
+            }
 
-        println!("info timeslice {time_slice}");
+            match self.commands.recv_timeout(POLL_INTERVAL) {
+                // Anything else is only valid once the search is over, and is parsed then against the
+                // position the commands before it have set up.
+                Ok(line) if !matches!(line.split_whitespace().next(), Some("stop" | "ponderhit" | "isready" | "quit")) => pending.push(line),
+                Ok(line) => match uci::parse_command(&line, &self.position.board) {
+                    Ok(Command::Stop) => break,
+                    Ok(Command::PonderHit) if pondering => {
+                        pondering = false;
+                        signals.ponder.store(false, Ordering::Relaxed);
+                        if !searching && !infinite {
+                            break;
+                        }
+                    }
+                    Ok(Command::IsReady) => self.isready(),
+                    Ok(Command::Quit) => {
+                        quit = true;
+                        break;
+                    }
+                    // A ponderhit while not pondering.
+                    Ok(_) => {}
+                    Err(err) => println!("info string error: {err}"),
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    quit = true;
+                    break;
+                }
+            }
+        }
 
+        signals.stop.store(true, Ordering::Relaxed); // cancel search
 
-        while time_slice > 0.0 {
-            let inst_now = Instant::now();
-            let res = rx.recv_timeout(Duration::from_secs_f64(time_slice));
-            if res.is_ok() {
-                (score, best_move, depth, nodes) = res.unwrap();
+        // The search unwinds right after the stop flag is set, but always finishes its first iteration.
+        self.engine = Some(search.join().expect("search thread panicked"));
+        for report in rx.try_iter() {
+            print_report(&report);
+            if let SearchReport::Iteration(info) = report {
+                if info.multi_pv == 1 {
+                    result = Some(info);
+                }
             }
+        }
 
-            let inst_after = Instant::now();
+        let pv = result.map(|info| info.pv).unwrap_or_default();
+        match pv.as_slice() {
+            [best_move, ponder_move, ..] => println!("bestmove {best_move} ponder {ponder_move}"),
+            [best_move] => println!("bestmove {best_move}"),
+            // No legal move, the UCI null move tells the GUI so.
+            [] => println!("bestmove 0000"),
+        }
 
-            let duration = inst_after.duration_since(inst_now).as_secs_f64();
-            time_slice = time_slice - duration;
+        if quit {
+            std::process::exit(0);
         }
 
-        let _ = tx_cancle.send(()); // cancel search
+        for command in pending {
+            self.handle_command(&command);
+        }
+    }
+}
 
+fn print_report(report: &SearchReport) {
+    match report {
+        SearchReport::Iteration(info) => {
+            let pv: Vec<String> = info.pv.iter().map(|mov| mov.to_string()).collect();
+            println!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {} time {} hashfull {} pv {}",
+                info.depth, info.seldepth, info.multi_pv, format_score(info.score), info.nodes, info.nps(), info.time.as_millis(), info.hashfull, pv.join(" ")
+            );
+        }
+        SearchReport::CurrentMove { depth, mov, number } => {
+            println!("info depth {depth} currmove {mov} currmovenumber {number}");
+        }
+    }
+}
 
-        println!("info score {} nodes {nodes} depth {depth}", score);
-        println!("bestmove {}", best_move.to_string())
+fn format_score(score: i32) -> String {
+    match Engine::mate_in(score) {
+        Some(moves) => format!("mate {moves}"),
+        None => format!("cp {score}"),
     }
 }
 
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+
+    // Stdin is read on its own thread so commands like "stop" reach us while a search is running.
+    let _ = thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    rx
+}
+
 fn main() {
     let mut uci_handler = UciHandler::new();
 
@@ -0,0 +1,379 @@
+use chess::{BitBoard, Board, ChessMove, Color, EMPTY, MoveGen, Piece, Rank, Square, get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rank, get_rook_moves};
+
+use crate::Engine::PIECE_VALUES;
+
+// The order the stages are gone through, each one only starts once the previous one is used up.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stage {
+    TtMove,
+    GenerateCaptures,
+    GoodCaptures,
+    Killers,
+    Countermove,
+    GenerateQuiets,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+/// Hands out the legal moves of a position one by one in the order they should be searched: the
+/// TT move, captures that do not lose material, the killers, the countermove, the quiet moves by
+/// their history and finally the losing captures.
+///
+/// Moves are only generated and scored once their stage is reached, so a node that fails high on
+/// the TT move never generates anything else. The TT move, killers and countermove are looked up
+/// among the moves the generator has for their destination square.
+pub struct MovePicker<'a> {
+    board: &'a Board,
+    move_gen: MoveGen,
+    stage: Stage,
+    tt_move: Option<ChessMove>,
+    killers: [Option<ChessMove>; 2],
+    killer_index: usize,
+    countermove: Option<ChessMove>,
+    // Moves of the current stage together with their scores.
+    moves: Vec<(ChessMove, i32)>,
+    bad_captures: Vec<(ChessMove, i32)>,
+    // Moves already taken from the generator that have neither been handed out nor put in a stage.
+    pending: Vec<ChessMove>,
+}
+
+impl<'a> MovePicker<'a> {
+    /// `move_gen` has to be a fresh legal move generator for `board`, with some moves possibly
+    /// removed. The TT move, killers and countermove may be illegal here, those are skipped.
+    pub fn new(board: &'a Board, move_gen: MoveGen, tt_move: Option<ChessMove>, killers: [Option<ChessMove>; 2], countermove: Option<ChessMove>) -> MovePicker<'a> {
+        MovePicker {
+            board,
+            move_gen,
+            stage: Stage::TtMove,
+            tt_move,
+            killers,
+            killer_index: 0,
+            countermove,
+            moves: Vec::new(),
+            bad_captures: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// The next move to search, quiet moves are ordered by `quiet_score` from high to low.
+    pub fn next(&mut self, quiet_score: impl Fn(ChessMove) -> i32) -> Option<ChessMove> {
+        loop {
+            match self.stage {
+                Stage::TtMove => {
+                    self.stage = Stage::GenerateCaptures;
+                    // Only 16 bits of the hash are compared, so a move from the table may belong to another position.
+                    if self.take_out(self.tt_move) {
+                        return self.tt_move;
+                    }
+                }
+                Stage::GenerateCaptures => {
+                    self.generate_captures();
+                    self.stage = Stage::GoodCaptures;
+                }
+                Stage::GoodCaptures => {
+                    match pick_best(&mut self.moves) {
+                        Some(mov) => return Some(mov),
+                        None => self.stage = Stage::Killers,
+                    }
+                }
+                Stage::Killers => {
+                    if self.killer_index == self.killers.len() {
+                        self.stage = Stage::Countermove;
+                        continue;
+                    }
+                    let killer = self.killers[self.killer_index];
+                    self.killer_index += 1;
+                    if self.take_out(killer) {
+                        return killer;
+                    }
+                }
+                Stage::Countermove => {
+                    self.stage = Stage::GenerateQuiets;
+                    if self.take_out(self.countermove) {
+                        return self.countermove;
+                    }
+                }
+                Stage::GenerateQuiets => {
+                    self.generate_quiets(&quiet_score);
+                    self.stage = Stage::Quiets;
+                }
+                Stage::Quiets => {
+                    match pick_best(&mut self.moves) {
+                        Some(mov) => return Some(mov),
+                        None => self.stage = Stage::BadCaptures,
+                    }
+                }
+                Stage::BadCaptures => {
+                    match pick_best(&mut self.bad_captures) {
+                        Some(mov) => return Some(mov),
+                        None => self.stage = Stage::Done,
+                    }
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+
+    fn generate_captures(&mut self) {
+        let board = self.board;
+        // Promotions are generated here too, and en passant is the only capture onto an empty square.
+        let promotion_rank = if board.side_to_move() == Color::White { Rank::Eighth } else { Rank::First };
+        let mut targets = board.color_combined(!board.side_to_move()) | get_rank(promotion_rank);
+        if let Some(square) = board.en_passant() {
+            targets |= BitBoard::from_square(square.uforward(board.side_to_move()));
+        }
+        self.move_gen.set_iterator_mask(targets);
+
+        // Quiet moves, taken out with the TT move or landing on the promotion rank, wait for the quiet stage.
+        let pending = std::mem::take(&mut self.pending);
+        for mov in pending.into_iter().chain(&mut self.move_gen) {
+            if is_quiet(board, mov) {
+                self.pending.push(mov);
+                continue;
+            }
+            let gain = capture_gain(board, mov);
+            // Winning at least what the attacker is worth cannot lose material, whatever the recaptures.
+            // That does not hold for promotions, the piece left on the square is worth more than the pawn.
+            if (gain >= 0 && mov.get_promotion().is_none()) || see(board, mov) >= 0 {
+                self.moves.push((mov, gain));
+            } else {
+                self.bad_captures.push((mov, gain));
+            }
+        }
+    }
+
+    fn generate_quiets(&mut self, quiet_score: &impl Fn(ChessMove) -> i32) {
+        self.move_gen.set_iterator_mask(!EMPTY);
+        for mov in self.pending.drain(..).chain(&mut self.move_gen) {
+            self.moves.push((mov, quiet_score(mov)));
+        }
+    }
+
+    /// Whether `mov` is a legal move that has not been handed out or put in a stage yet, in which case
+    /// it is taken out of the remaining moves. Only the moves to its destination square are generated,
+    /// the others to that square wait in `pending`.
+    fn take_out(&mut self, mov: Option<ChessMove>) -> bool {
+        let Some(mov) = mov else {
+            return false;
+        };
+        self.move_gen.set_iterator_mask(BitBoard::from_square(mov.get_dest()));
+        self.pending.extend(&mut self.move_gen);
+        match self.pending.iter().position(|&pending| pending == mov) {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Removes and returns the move with the highest score, the first one among equal scores.
+fn pick_best(moves: &mut Vec<(ChessMove, i32)>) -> Option<ChessMove> {
+    if moves.is_empty() {
+        return None;
+    }
+    let mut best = 0;
+    for (index, &(_, score)) in moves.iter().enumerate() {
+        if score > moves[best].1 {
+            best = index;
+        }
+    }
+    Some(moves.remove(best).0)
+}
+
+/// Neither a capture nor a promotion.
+pub fn is_quiet(board: &Board, mov: ChessMove) -> bool {
+    // A pawn changing files always captures, en passant leaves the destination empty.
+    let capture = board.piece_on(mov.get_dest()).is_some()
+        || (board.piece_on(mov.get_source()) == Some(Piece::Pawn) && mov.get_source().get_file() != mov.get_dest().get_file());
+    !capture && mov.get_promotion().is_none()
+}
+
+/// Value of the captured piece plus what a promotion adds, minus the value of the moving piece.
+fn capture_gain(board: &Board, mov: ChessMove) -> i32 {
+    let attacker = board.piece_on(mov.get_source()).unwrap();
+    captured_value(board, mov) + promotion_value(mov) - PIECE_VALUES[attacker.to_index()]
+}
+
+fn captured_value(board: &Board, mov: ChessMove) -> i32 {
+    match board.piece_on(mov.get_dest()) {
+        Some(victim) => PIECE_VALUES[victim.to_index()],
+        // En passant is the only capture onto an empty square.
+        None if board.piece_on(mov.get_source()) == Some(Piece::Pawn) && mov.get_source().get_file() != mov.get_dest().get_file() => PIECE_VALUES[Piece::Pawn.to_index()],
+        None => 0,
+    }
+}
+
+fn promotion_value(mov: ChessMove) -> i32 {
+    mov.get_promotion().map_or(0, |piece| PIECE_VALUES[piece.to_index()] - PIECE_VALUES[Piece::Pawn.to_index()])
+}
+
+/// Static exchange evaluation: the material `mov` wins once both sides have made every recapture on
+/// its destination that pays off for them, always with their cheapest piece. Pins are ignored.
+pub fn see(board: &Board, mov: ChessMove) -> i32 {
+    let dest = mov.get_dest();
+    let mut occupied = *board.combined() ^ BitBoard::from_square(mov.get_source());
+    // The pawn taken en passant is not on the destination square.
+    if board.piece_on(dest).is_none() && captured_value(board, mov) != 0 {
+        if let Some(square) = board.en_passant() {
+            occupied ^= BitBoard::from_square(square);
+        }
+    }
+
+    // gains[i] is what the side making the i-th capture has won so far, if the other side stops there.
+    let mut gains = vec![captured_value(board, mov) + promotion_value(mov)];
+    let mut on_square = match mov.get_promotion() {
+        Some(piece) => PIECE_VALUES[piece.to_index()],
+        None => PIECE_VALUES[board.piece_on(mov.get_source()).unwrap().to_index()],
+    };
+    let mut side = !board.side_to_move();
+
+    while let Some((piece, square)) = cheapest_attacker(board, dest, occupied, side) {
+        gains.push(on_square - gains[gains.len() - 1]);
+        on_square = PIECE_VALUES[piece.to_index()];
+        occupied ^= BitBoard::from_square(square);
+        side = !side;
+    }
+
+    // Going backwards, every side only captures if that is better than stopping.
+    while gains.len() > 1 {
+        let last = gains.pop().unwrap();
+        let previous = gains.len() - 1;
+        gains[previous] = gains[previous].min(-last);
+    }
+    gains[0]
+}
+
+/// The least valuable piece of `side` among `occupied` that attacks `square`. Removing pieces from
+/// `occupied` uncovers the sliders behind them.
+fn cheapest_attacker(board: &Board, square: Square, occupied: BitBoard, side: Color) -> Option<(Piece, Square)> {
+    let own = board.color_combined(side) & occupied;
+    let diagonal = get_bishop_moves(square, occupied);
+    let straight = get_rook_moves(square, occupied);
+
+    for piece in [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen, Piece::King] {
+        let attacks = match piece {
+            // The squares a pawn of the other side on `square` would attack.
+            Piece::Pawn => get_pawn_attacks(square, !side, !EMPTY),
+            Piece::Knight => get_knight_moves(square),
+            Piece::Bishop => diagonal,
+            Piece::Rook => straight,
+            Piece::Queen => diagonal | straight,
+            Piece::King => get_king_moves(square),
+        };
+        let attackers = attacks & board.pieces(piece) & own;
+        if attackers != EMPTY {
+            return Some((piece, attackers.to_square()));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn mov(text: &str) -> ChessMove {
+        ChessMove::from_str(text).unwrap()
+    }
+
+    fn pick_all(fen: &str, tt_move: Option<&str>, killers: [Option<&str>; 2], countermove: Option<&str>) -> Vec<ChessMove> {
+        let board = Board::from_str(fen).unwrap();
+        let mut picker = MovePicker::new(&board, MoveGen::new_legal(&board), tt_move.map(mov), killers.map(|killer| killer.map(mov)), countermove.map(mov));
+        let mut moves = Vec::new();
+        while let Some(mov) = picker.next(|_| 0) {
+            moves.push(mov);
+        }
+        moves
+    }
+
+    fn assert_each_legal_move_once(fen: &str, moves: &[ChessMove]) {
+        let board = Board::from_str(fen).unwrap();
+        let legal: HashSet<ChessMove> = MoveGen::new_legal(&board).collect();
+        let picked: HashSet<ChessMove> = moves.iter().copied().collect();
+        assert_eq!(moves.len(), picked.len(), "a move was returned twice in {fen}");
+        assert_eq!(picked, legal, "{fen}");
+    }
+
+    #[test]
+    fn returns_each_legal_move_once() {
+        let cases = [
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", None, [None, None], None),
+            // Other moves to the squares of the TT move and the killers, f2f3 and b1c3 are taken out with them.
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", Some("g1f3"), [Some("c2c3"), Some("f2f3")], Some("b1c3")),
+            // Killer and countermove that are the TT move, illegal or a capture.
+            ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", Some("e2a6"), [Some("e2a6"), Some("a1b1")], Some("a1b1")),
+            ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", Some("e1g1"), [Some("h7h8"), Some("d5e6")], Some("g2h3")),
+            // En passant and promotions with and without capture.
+            ("4k3/1P6/8/3pP3/8/8/6p1/4K2R w K d6 0 1", Some("b7b8n"), [Some("b7b8q"), Some("e1g1")], Some("e5e6")),
+            ("4k3/1P6/8/3pP3/8/8/6p1/4K2R b - - 0 1", Some("g2h1q"), [Some("g2g1r"), None], None),
+            // In check, only a few moves are legal.
+            ("4k3/8/8/8/8/8/3q4/4K3 w - - 0 1", Some("e1d2"), [Some("e1f2"), Some("e1e2")], Some("e1f1")),
+            // No legal moves at all.
+            ("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", Some("h8g8"), [None, None], None),
+        ];
+        for (fen, tt_move, killers, countermove) in cases {
+            assert_each_legal_move_once(fen, &pick_all(fen, tt_move, killers, countermove));
+        }
+    }
+
+    #[test]
+    fn stages_come_in_order() {
+        // Rxa5 loses the rook to the pawn on b6, Rxe5 wins a free knight.
+        let fen = "4k3/8/1p6/n3n3/8/8/8/R3R1K1 w - - 0 1";
+        let moves = pick_all(fen, Some("g1h1"), [Some("a1a2"), Some("e1f1")], Some("e1e2"));
+
+        assert_eq!(&moves[..5], &[mov("g1h1"), mov("e1e5"), mov("a1a2"), mov("e1f1"), mov("e1e2")]);
+        assert_eq!(moves.last(), Some(&mov("a1a5")));
+        assert_each_legal_move_once(fen, &moves);
+    }
+
+    #[test]
+    fn promotions_onto_a_defended_square_lose() {
+        // Promoting on a8 only gives the rook a piece to take, taking the rook is safe from the king.
+        let fen = "1r2k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        let moves = pick_all(fen, None, [None, None], None);
+
+        assert_eq!(&moves[..4], &[mov("a7b8q"), mov("a7b8r"), mov("a7b8b"), mov("a7b8n")]);
+        let last: HashSet<ChessMove> = moves[moves.len() - 4..].iter().copied().collect();
+        assert_eq!(last, HashSet::from([mov("a7a8q"), mov("a7a8r"), mov("a7a8b"), mov("a7a8n")]));
+        assert_each_legal_move_once(fen, &moves);
+    }
+
+    #[test]
+    fn quiet_moves_by_score() {
+        let board = Board::from_str("4k3/8/8/8/8/8/8/R3K3 w - - 0 1").unwrap();
+        let mut picker = MovePicker::new(&board, MoveGen::new_legal(&board), None, [None, None], None);
+        let score = |mov: ChessMove| if mov == ChessMove::from_str("a1a8").unwrap() { 100 } else { mov.get_dest().to_index() as i32 };
+
+        assert_eq!(picker.next(score), Some(mov("a1a8")));
+        assert_eq!(picker.next(score), Some(mov("a1a7")));
+        assert_eq!(picker.next(score), Some(mov("a1a6")));
+    }
+
+    #[test]
+    fn static_exchange() {
+        let cases = [
+            // Undefended knight.
+            ("4k3/8/8/4n3/8/8/8/4R1K1 w - - 0 1", "e1e5", 320),
+            // Knight defended by a pawn.
+            ("4k3/8/3p4/4n3/8/8/8/4R1K1 w - - 0 1", "e1e5", 320 - 500),
+            // The second rook behind the first one recaptures.
+            ("4k3/4r3/8/4p3/8/8/4R3/4R1K1 w - - 0 1", "e2e5", 100),
+            // A pawn takes a queen, first undefended and then defended by a rook.
+            ("4k3/8/8/3q4/4P3/8/8/6K1 w - - 0 1", "e4d5", 900),
+            ("4k3/3r4/8/3q4/4P3/8/8/6K1 w - - 0 1", "e4d5", 900 - 100),
+            // En passant onto a square that is defended.
+            ("4k3/2p5/8/3pP3/8/8/8/6K1 w - d6 0 1", "e5d6", 0),
+        ];
+        for (fen, text, value) in cases {
+            let board = Board::from_str(fen).unwrap();
+            assert_eq!(see(&board, mov(text)), value, "{fen} {text}");
+        }
+    }
+}
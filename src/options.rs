@@ -0,0 +1,98 @@
+use crate::time_manager;
+
+pub const HASH: &str = "Hash";
+pub const THREADS: &str = "Threads";
+pub const CLEAR_HASH: &str = "Clear Hash";
+pub const MOVE_OVERHEAD: &str = "Move Overhead";
+pub const MULTI_PV: &str = "MultiPV";
+pub const PONDER: &str = "Ponder";
+
+#[derive(Clone, Copy, Debug)]
+enum OptionType {
+    Spin { default: i64, min: i64, max: i64 },
+    Check { default: bool },
+    Button,
+}
+
+struct UciOption {
+    name: &'static str,
+    option_type: OptionType,
+    value: i64,
+}
+
+impl UciOption {
+    fn spin(name: &'static str, default: i64, min: i64, max: i64) -> UciOption {
+        UciOption { name, option_type: OptionType::Spin { default, min, max }, value: default }
+    }
+
+    fn check(name: &'static str, default: bool) -> UciOption {
+        UciOption { name, option_type: OptionType::Check { default }, value: default as i64 }
+    }
+
+    fn button(name: &'static str) -> UciOption {
+        UciOption { name, option_type: OptionType::Button, value: 0 }
+    }
+}
+
+/// All options the engine announces on "uci" and accepts with "setoption".
+pub struct Options {
+    options: Vec<UciOption>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            options: vec![
+                UciOption::spin(HASH, 64, 1, 4096),
+                UciOption::spin(THREADS, 1, 1, 256),
+                UciOption::button(CLEAR_HASH),
+                UciOption::spin(MOVE_OVERHEAD, time_manager::DEFAULT_MOVE_OVERHEAD.as_millis() as i64, 0, 5000),
+                UciOption::spin(MULTI_PV, 1, 1, 256),
+                // Only tells the GUI that we can ponder, the GUI decides when to send "go ponder".
+                UciOption::check(PONDER, false),
+            ],
+        }
+    }
+
+    pub fn print(&self) {
+        for option in &self.options {
+            match option.option_type {
+                OptionType::Spin { default, min, max } => {
+                    println!("option name {} type spin default {default} min {min} max {max}", option.name)
+                }
+                OptionType::Check { default } => println!("option name {} type check default {default}", option.name),
+                OptionType::Button => println!("option name {} type button", option.name),
+            }
+        }
+    }
+
+    /// Sets the option called `name` (case insensitive) and returns its canonical name, so the
+    /// caller can react to the change. Buttons take no value, checks are stored as 0 or 1.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<&'static str, String> {
+        let option = self.options.iter_mut()
+            .find(|option| option.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown option {name}"))?;
+
+        match option.option_type {
+            OptionType::Spin { min, max, .. } => {
+                let value = value.ok_or_else(|| format!("option {} needs a value", option.name))?;
+                let value: i64 = value.parse().map_err(|_| format!("invalid value {value} for option {}", option.name))?;
+                option.value = value.clamp(min, max);
+            }
+            OptionType::Check { .. } => {
+                option.value = match value {
+                    Some("true") => 1,
+                    Some("false") => 0,
+                    _ => return Err(format!("option {} needs true or false", option.name)),
+                };
+            }
+            OptionType::Button => {}
+        }
+
+        Ok(option.name)
+    }
+
+    pub fn get(&self, name: &str) -> i64 {
+        self.options.iter().find(|option| option.name == name).map_or(0, |option| option.value)
+    }
+}
@@ -0,0 +1,125 @@
+use std::str::FromStr;
+
+use chess::{ALL_COLORS, ALL_SQUARES, Board, BoardBuilder, ChessMove, Color, Piece};
+
+const DEFAULT_FEN_FIELDS: [&str; 6] = ["", "w", "-", "-", "0", "1"];
+
+/// A board together with the move counters of its FEN, which `chess::Board` does not track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub board: Board,
+    /// Half moves since the last capture or pawn move, for the fifty-move rule.
+    pub halfmove_clock: u16,
+    pub fullmove_number: u16,
+    /// Half moves since the last null move of the search, `u16::MAX` without one. No position before
+    /// a null move can come back, just like with a capture or pawn move.
+    pub plies_since_null_move: u16,
+}
+
+impl Default for Position {
+    fn default() -> Position {
+        Position { board: Board::default(), halfmove_clock: 0, fullmove_number: 1, plies_since_null_move: u16::MAX }
+    }
+}
+
+impl Position {
+    /// Parses a FEN with one to six fields, the missing ones default to "w - - 0 1".
+    pub fn from_fen(fen: &str) -> Option<Position> {
+        let mut fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.is_empty() || fields.len() > DEFAULT_FEN_FIELDS.len() {
+            return None;
+        }
+        fields.extend_from_slice(&DEFAULT_FEN_FIELDS[fields.len()..]);
+
+        let builder = BoardBuilder::from_str(&fields[..4].join(" ")).ok()?;
+
+        // Board::try_from looks up both kings before it checks the position, so they have to be there.
+        for color in ALL_COLORS {
+            let kings = ALL_SQUARES.iter().filter(|&&square| builder[square] == Some((Piece::King, color))).count();
+            if kings != 1 {
+                return None;
+            }
+        }
+
+        Some(Position {
+            board: Board::try_from(builder).ok()?,
+            halfmove_clock: fields[4].parse().ok()?,
+            // Some GUIs send 0 here, the count starts at 1 though.
+            fullmove_number: fields[5].parse::<u16>().ok()?.max(1),
+            plies_since_null_move: u16::MAX,
+        })
+    }
+
+    pub fn make_move_new(&self, mov: ChessMove) -> Position {
+        let irreversible = self.board.piece_on(mov.get_source()) == Some(Piece::Pawn)
+            || self.board.piece_on(mov.get_dest()).is_some();
+
+        Position {
+            board: self.board.make_move_new(mov),
+            halfmove_clock: if irreversible { 0 } else { self.halfmove_clock.saturating_add(1) },
+            fullmove_number: self.fullmove_number.saturating_add((self.board.side_to_move() == Color::Black) as u16),
+            plies_since_null_move: self.plies_since_null_move.saturating_add(1),
+        }
+    }
+
+    /// Lets the opponent move again, `None` when in check.
+    pub fn make_null_move(&self) -> Option<Position> {
+        Some(Position {
+            board: self.board.null_move()?,
+            halfmove_clock: self.halfmove_clock.saturating_add(1),
+            fullmove_number: self.fullmove_number.saturating_add((self.board.side_to_move() == Color::Black) as u16),
+            plies_since_null_move: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::Square;
+
+    use super::*;
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        let position = Position::from_fen("4k3/8/8/8/8/8/8/4K3").unwrap();
+        assert_eq!(position.board.side_to_move(), Color::White);
+        assert_eq!(position.halfmove_clock, 0);
+        assert_eq!(position.fullmove_number, 1);
+
+        let position = Position::from_fen("4k3/8/8/8/8/8/8/4K3 b - - 37").unwrap();
+        assert_eq!(position.board.side_to_move(), Color::Black);
+        assert_eq!(position.halfmove_clock, 37);
+        assert_eq!(position.fullmove_number, 1);
+    }
+
+    #[test]
+    fn counters_follow_the_moves() {
+        let position = Position::from_fen("4k3/4p3/8/8/8/8/8/4K3 w - - 10 42").unwrap();
+
+        let position = position.make_move_new(ChessMove::new(Square::E1, Square::D1, None));
+        assert_eq!((position.halfmove_clock, position.fullmove_number), (11, 42));
+
+        let position = position.make_move_new(ChessMove::new(Square::E8, Square::D8, None));
+        assert_eq!((position.halfmove_clock, position.fullmove_number), (12, 43));
+
+        let position = position.make_move_new(ChessMove::new(Square::D1, Square::C1, None));
+        let position = position.make_move_new(ChessMove::new(Square::E7, Square::E5, None));
+        assert_eq!((position.halfmove_clock, position.fullmove_number), (0, 44));
+    }
+
+    #[test]
+    fn fullmove_number_stops_at_the_maximum() {
+        let position = Position::from_fen("4k3/8/8/8/8/8/8/4K3 b - - 0 65535").unwrap();
+        assert_eq!(position.make_move_new(ChessMove::new(Square::E8, Square::D8, None)).fullmove_number, u16::MAX);
+        assert_eq!(position.make_null_move().unwrap().fullmove_number, u16::MAX);
+    }
+
+    #[test]
+    fn invalid_fens() {
+        assert_eq!(Position::from_fen(""), None);
+        assert_eq!(Position::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1 extra"), None);
+        assert_eq!(Position::from_fen("4k3/8/8/8/8/8/8/4K3 w - - x 1"), None);
+        assert_eq!(Position::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 -1"), None);
+        assert_eq!(Position::from_fen("8/8/8/8/8/8/8/8"), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::{Duration, Instant};
+
+use chess::{Board, Color, Piece};
+
+/// Time reserved per move for communication with the GUI, so we never lose on time.
+pub const DEFAULT_MOVE_OVERHEAD: Duration = Duration::from_millis(30);
+
+// Never plan with fewer than this many moves left in the game when there is no "movestogo".
+const MIN_MOVES_LEFT: u32 = 20;
+// Extra moves assumed to be left in the game when all the pieces are still on the board.
+const OPENING_EXTRA_MOVES: u32 = 25;
+// How far the hard limit may exceed the planned time for a move.
+const HARD_LIMIT_FACTOR: u32 = 4;
+
+// Scales the soft limit by how many iterations in a row returned the same best move.
+const STABILITY_SCALE: [f64; 6] = [2.0, 1.4, 1.1, 0.9, 0.75, 0.5];
+// A score drop of this many centipawns compared to the last iteration doubles the soft limit.
+const SCORE_DROP_FULL_SCALE: i32 = 100;
+
+/// The clock situation as sent with "go", all times in milliseconds.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct TimeControl {
+    pub white_time: Option<u64>,
+    pub black_time: Option<u64>,
+    pub white_increment: u64,
+    pub black_increment: u64,
+    pub moves_to_go: Option<u32>,
+    pub move_time: Option<u64>,
+}
+
+impl TimeControl {
+    pub fn is_empty(&self) -> bool {
+        self.white_time.is_none() && self.black_time.is_none() && self.move_time.is_none()
+    }
+}
+
+/// Decides how long a search may take.
+///
+/// Once the (scaled) soft limit is reached no new iteration is started, the hard limit aborts the
+/// search mid-iteration.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeManager {
+    start: Instant,
+    soft_limit: Duration,
+    hard_limit: Duration,
+    // A "movetime" search, which always uses up its time.
+    fixed_time: bool,
+}
+
+impl TimeManager {
+    pub fn new(time_control: &TimeControl, board: &Board, move_overhead: Duration) -> TimeManager {
+        let start = Instant::now();
+        let overhead = move_overhead.as_millis() as u64;
+
+        if let Some(move_time) = time_control.move_time {
+            let limit = Duration::from_millis(move_time.saturating_sub(overhead).max(1));
+            return TimeManager { start, soft_limit: limit, hard_limit: limit, fixed_time: true };
+        }
+
+        let (time_left, increment) = if board.side_to_move() == Color::White {
+            (time_control.white_time, time_control.white_increment)
+        } else {
+            (time_control.black_time, time_control.black_increment)
+        };
+
+        let available = time_left.unwrap_or(0).saturating_sub(overhead).max(1);
+        let moves_left = match time_control.moves_to_go {
+            Some(moves_to_go) => moves_to_go.clamp(1, 50),
+            None => estimate_moves_left(board),
+        } as u64;
+
+        // Any u64 is accepted from the GUI, so none of this may overflow.
+        let planned = (available / moves_left).saturating_add(increment.saturating_mul(3) / 4);
+        // With only one move left before the next time control we may use nearly everything.
+        let max_hard = if moves_left == 1 { available } else { available - available / 4 };
+        let hard_limit = planned.saturating_mul(HARD_LIMIT_FACTOR as u64).min(max_hard).max(1);
+        let soft_limit = planned.min(hard_limit);
+
+        TimeManager {
+            start,
+            soft_limit: Duration::from_millis(soft_limit),
+            hard_limit: Duration::from_millis(hard_limit),
+            fixed_time: false,
+        }
+    }
+
+    /// Starts counting from now, used when a ponder search turns into a real one.
+    pub fn restart(&mut self) {
+        self.start = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Called after every finished iteration, `stable_iterations` is the number of iterations the
+    /// best move stayed the same and `score_drop` how much worse the score got since the last one.
+    ///
+    /// An unstable best move or a falling score stretch the soft limit towards the hard limit, a
+    /// best move that has not changed for several iterations lets the search stop early.
+    pub fn should_stop(&self, stable_iterations: usize, score_drop: i32) -> bool {
+        if self.fixed_time {
+            return self.hard_limit_reached();
+        }
+
+        let mut scale = STABILITY_SCALE[stable_iterations.min(STABILITY_SCALE.len() - 1)];
+        scale *= 1.0 + score_drop.clamp(0, SCORE_DROP_FULL_SCALE) as f64 / SCORE_DROP_FULL_SCALE as f64;
+
+        let limit = self.soft_limit.mul_f64(scale).min(self.hard_limit);
+        self.elapsed() >= limit
+    }
+
+    pub fn hard_limit_reached(&self) -> bool {
+        self.elapsed() >= self.hard_limit
+    }
+}
+
+/// Estimates the number of moves left in the game from the material still on the board.
+fn estimate_moves_left(board: &Board) -> u32 {
+    let phase = board.pieces(Piece::Knight).popcnt()
+        + board.pieces(Piece::Bishop).popcnt()
+        + 2 * board.pieces(Piece::Rook).popcnt()
+        + 4 * board.pieces(Piece::Queen).popcnt();
+
+    MIN_MOVES_LEFT + OPENING_EXTRA_MOVES * phase.min(24) / 24
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERHEAD: Duration = DEFAULT_MOVE_OVERHEAD;
+
+    fn white_to_move(white_time: u64, white_increment: u64, moves_to_go: Option<u32>) -> TimeManager {
+        let time_control = TimeControl { white_time: Some(white_time), black_time: Some(1000), white_increment, moves_to_go, ..TimeControl::default() };
+        TimeManager::new(&time_control, &Board::default(), OVERHEAD)
+    }
+
+    #[test]
+    fn move_overhead_is_subtracted() {
+        let time_control = TimeControl { move_time: Some(1000), ..TimeControl::default() };
+        let time = TimeManager::new(&time_control, &Board::default(), OVERHEAD);
+        assert_eq!(time.hard_limit, Duration::from_millis(1000) - OVERHEAD);
+        assert_eq!(time.soft_limit, time.hard_limit);
+
+        // The last move before the time control may use everything but the overhead.
+        let time = white_to_move(10_000, 0, Some(1));
+        assert_eq!(time.hard_limit, Duration::from_millis(10_000) - OVERHEAD);
+    }
+
+    #[test]
+    fn hard_limit_stays_within_the_time_left() {
+        let cases = [(60_000, 0, None), (1000, 5000, None), (500, 0, Some(1)), (20, 0, None), (100_000, 100, Some(40))];
+        for (time_left, increment, moves_to_go) in cases {
+            let time = white_to_move(time_left, increment, moves_to_go);
+            let available = Duration::from_millis(time_left).saturating_sub(OVERHEAD).max(Duration::from_millis(1));
+            assert!(time.hard_limit <= available, "{time_left} {increment} {moves_to_go:?}: {time:?}");
+            assert!(time.soft_limit <= time.hard_limit, "{time:?}");
+        }
+    }
+
+    #[test]
+    fn huge_clock_values() {
+        let time = white_to_move(u64::MAX, 0, Some(1));
+        assert_eq!(time.hard_limit, Duration::from_millis(u64::MAX) - OVERHEAD);
+        assert!(time.soft_limit <= time.hard_limit);
+
+        // The increment is only added after this move, three quarters of the 970ms left are the most.
+        let time = white_to_move(1000, u64::MAX, None);
+        assert_eq!(time.hard_limit, Duration::from_millis(728));
+        assert_eq!(time.soft_limit, time.hard_limit);
+    }
+}
@@ -0,0 +1,288 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use chess::{ALL_SQUARES, ChessMove, Piece};
+
+const BUCKET_SIZE: usize = 4;
+// The generation wraps around after this many searches.
+const GENERATIONS: u8 = 1 << 6;
+// How many plies of depth a search of age one is worth when picking the entry to replace.
+const AGE_WEIGHT: i32 = 8;
+// "hashfull" looks at this many entries from the start of the table.
+const HASHFULL_SAMPLE: usize = 1000;
+
+const PROMOTIONS: [Piece; 4] = [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TranspositionEntry {
+    pub mov: Option<ChessMove>,
+    pub score: i32,
+    pub node_type: NodeType,
+    pub depth: u16,
+}
+
+/// The kind of bound the stored score is: exact for PV nodes, a lower bound for nodes that failed
+/// high and an upper bound for nodes where no move reached alpha.
+#[derive(Clone, Copy, PartialOrd, PartialEq, Debug)]
+pub enum NodeType {
+    PVNode,
+    AllNode,
+    CutNode,
+}
+
+impl TranspositionEntry {
+    /// The score to return right away if this entry comes from a search at least `depth` deep and
+    /// decides the window (alpha, beta) on its own.
+    pub fn cutoff(&self, alpha: i32, beta: i32, depth: u16) -> Option<i32> {
+        if self.depth < depth {
+            return None;
+        }
+        match self.node_type {
+            NodeType::PVNode => Some(self.score.clamp(alpha, beta)),
+            NodeType::CutNode if self.score >= beta => Some(beta),
+            NodeType::AllNode if self.score <= alpha => Some(alpha),
+            _ => None,
+        }
+    }
+}
+
+// Four entries share a cache line, so a probe touches memory only once.
+#[derive(Default)]
+#[repr(align(32))]
+struct Bucket([AtomicU64; BUCKET_SIZE]);
+
+/// A hash table of search results, shared between the search threads without any locking.
+///
+/// Every entry is packed into a single `u64`, so it is written and read in one piece:
+///
+/// | bits  | content                                          |
+/// |-------|--------------------------------------------------|
+/// | 0-15  | upper 16 bits of the position hash               |
+/// | 16-31 | move (from, to, promotion), 0 for none           |
+/// | 32-47 | score                                            |
+/// | 48-55 | depth                                            |
+/// | 56-57 | node type, 0 marks an empty entry                |
+/// | 58-63 | generation of the search that stored the entry   |
+///
+/// The lower bits of the hash pick the bucket. When a bucket is full the entry with the lowest
+/// depth is replaced, entries from older searches count as shallower the older they are.
+pub struct TranspositionTable {
+    buckets: Vec<Bucket>,
+    generation: AtomicU8,
+}
+
+impl TranspositionTable {
+    /// Creates a table that uses at most `mb` megabytes.
+    pub fn new(mb: usize) -> TranspositionTable {
+        let buckets = ((mb.max(1) << 20) / size_of::<Bucket>()).max(1);
+        TranspositionTable {
+            buckets: (0..buckets).map(|_| Bucket::default()).collect(),
+            generation: AtomicU8::new(0),
+        }
+    }
+
+    pub fn clear(&self) {
+        for slot in self.buckets.iter().flat_map(|bucket| &bucket.0) {
+            slot.store(0, Ordering::Relaxed);
+        }
+        self.generation.store(0, Ordering::Relaxed);
+    }
+
+    /// Marks the entries stored so far as older, called once before every search.
+    pub fn new_search(&self) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.generation.store((generation + 1) % GENERATIONS, Ordering::Relaxed);
+    }
+
+    pub fn probe(&self, hash: u64) -> Option<TranspositionEntry> {
+        let key = key(hash);
+        self.bucket(hash).0.iter()
+            .map(|slot| slot.load(Ordering::Relaxed))
+            .find(|&data| !is_empty(data) && data as u16 == key)
+            .map(unpack)
+    }
+
+    pub fn store(&self, hash: u64, entry: TranspositionEntry) {
+        let key = key(hash);
+        let bucket = &self.bucket(hash).0;
+        let generation = self.generation.load(Ordering::Relaxed);
+
+        let mut replace = &bucket[0];
+        let mut replace_value = i32::MAX;
+        for slot in bucket {
+            let data = slot.load(Ordering::Relaxed);
+            // Empty slots are all zero, so they have no move to keep.
+            if is_empty(data) || data as u16 == key {
+                // Keep the known best move when the new result has none.
+                let mov = entry.mov.or(unpack(data).mov);
+                slot.store(pack(key, TranspositionEntry { mov, ..entry }, generation), Ordering::Relaxed);
+                return;
+            }
+
+            let value = unpack(data).depth as i32 - AGE_WEIGHT * self.age(data) as i32;
+            if value < replace_value {
+                replace = slot;
+                replace_value = value;
+            }
+        }
+
+        replace.store(pack(key, entry, generation), Ordering::Relaxed);
+    }
+
+    /// Permille of the entries that were stored during the current search.
+    pub fn hashfull(&self) -> u16 {
+        let used = self.buckets.iter()
+            .flat_map(|bucket| &bucket.0)
+            .take(HASHFULL_SAMPLE)
+            .map(|slot| slot.load(Ordering::Relaxed))
+            .filter(|&data| !is_empty(data) && self.age(data) == 0)
+            .count();
+        let sampled = HASHFULL_SAMPLE.min(self.buckets.len() * BUCKET_SIZE);
+        (used * 1000 / sampled) as u16
+    }
+
+    fn bucket(&self, hash: u64) -> &Bucket {
+        // Maps the lower 32 bits evenly onto the buckets, the table size need not be a power of two.
+        let index = ((hash as u32 as u64) * self.buckets.len() as u64) >> 32;
+        &self.buckets[index as usize]
+    }
+
+    fn age(&self, data: u64) -> u8 {
+        (self.generation.load(Ordering::Relaxed) + GENERATIONS - (data >> 58) as u8) % GENERATIONS
+    }
+}
+
+fn key(hash: u64) -> u16 {
+    (hash >> 48) as u16
+}
+
+fn is_empty(data: u64) -> bool {
+    (data >> 56) & 0b11 == 0
+}
+
+fn pack(key: u16, entry: TranspositionEntry, generation: u8) -> u64 {
+    let node_type: u64 = match entry.node_type {
+        NodeType::PVNode => 1,
+        NodeType::AllNode => 2,
+        NodeType::CutNode => 3,
+    };
+    let score = entry.score.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+    key as u64
+        | (entry.mov.map_or(0, pack_move) as u64) << 16
+        | (score as u16 as u64) << 32
+        | (entry.depth.min(u8::MAX as u16) as u64) << 48
+        | node_type << 56
+        | (generation as u64) << 58
+}
+
+fn unpack(data: u64) -> TranspositionEntry {
+    let node_type = match (data >> 56) & 0b11 {
+        1 => NodeType::PVNode,
+        2 => NodeType::AllNode,
+        _ => NodeType::CutNode,
+    };
+
+    TranspositionEntry {
+        mov: unpack_move((data >> 16) as u16),
+        score: (data >> 32) as u16 as i16 as i32,
+        node_type,
+        depth: (data >> 48) as u8 as u16,
+    }
+}
+
+fn pack_move(mov: ChessMove) -> u16 {
+    let promotion = mov.get_promotion()
+        .and_then(|piece| PROMOTIONS.iter().position(|&promotion| promotion == piece))
+        .map_or(0, |index| index as u16 + 1);
+    mov.get_source().to_index() as u16 | (mov.get_dest().to_index() as u16) << 6 | promotion << 12
+}
+
+/// A move never starts and ends on the same square, so 0 is free to stand for no move.
+fn unpack_move(data: u16) -> Option<ChessMove> {
+    if data == 0 {
+        return None;
+    }
+    let source = ALL_SQUARES[(data & 63) as usize];
+    let dest = ALL_SQUARES[(data >> 6 & 63) as usize];
+    let promotion = match data >> 12 {
+        0 => None,
+        index => Some(PROMOTIONS[index as usize - 1]),
+    };
+    Some(ChessMove::new(source, dest, promotion))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn entry(mov: &str, score: i32, depth: u16) -> TranspositionEntry {
+        TranspositionEntry { mov: ChessMove::from_str(mov).ok(), score, node_type: NodeType::CutNode, depth }
+    }
+
+    #[test]
+    fn entries_survive_packing() {
+        let table = TranspositionTable::new(1);
+        let entries = [
+            (0x1234_5678_9abc_def0, entry("e2e4", 35, 7)),
+            (0xfedc_ba98_7654_3210, entry("a7a8q", -31_990, 1)),
+            (0x0f0f_0f0f_0f0f_0f0f, entry("h2h1n", 0, 99)),
+            (0xf0f0_f0f0_f0f0_f0f0, TranspositionEntry { mov: None, score: -1, node_type: NodeType::AllNode, depth: 3 }),
+        ];
+
+        for (hash, entry) in entries {
+            table.store(hash, entry);
+            assert_eq!(table.probe(hash), Some(entry));
+        }
+        assert_eq!(table.probe(0x1234_0000_0000_0000), None);
+    }
+
+    #[test]
+    fn missing_move_keeps_the_old_one() {
+        let table = TranspositionTable::new(1);
+        table.store(42, entry("g1f3", 10, 4));
+        table.store(42, TranspositionEntry { mov: None, score: -5, node_type: NodeType::AllNode, depth: 6 });
+
+        let stored = table.probe(42).unwrap();
+        assert_eq!(stored.mov, ChessMove::from_str("g1f3").ok());
+        assert_eq!((stored.score, stored.depth, stored.node_type), (-5, 6, NodeType::AllNode));
+    }
+
+    #[test]
+    fn replaces_shallow_and_old_entries() {
+        let table = TranspositionTable::new(1);
+        // Same lower bits, so all of them go into the same bucket.
+        let hash = |n: u64| n << 48 | 7;
+
+        for n in 1..=BUCKET_SIZE as u64 {
+            table.store(hash(n), entry("e2e4", 0, 10 + n as u16));
+        }
+        table.store(hash(9), entry("e2e4", 0, 5));
+        assert_eq!(table.probe(hash(1)), None);
+        assert!(table.probe(hash(2)).is_some());
+
+        // Two searches later even the depth 12 entry goes before a fresh depth 1 one.
+        table.new_search();
+        table.new_search();
+        table.store(hash(10), entry("e2e4", 0, 1));
+        table.store(hash(11), entry("e2e4", 0, 1));
+        assert_eq!(table.probe(hash(9)), None);
+        assert_eq!(table.probe(hash(2)), None);
+        assert!(table.probe(hash(10)).is_some());
+    }
+
+    #[test]
+    fn hashfull_counts_the_current_search() {
+        let table = TranspositionTable::new(1);
+        assert_eq!(table.hashfull(), 0);
+
+        for n in 0..table.buckets.len() as u64 * 4 {
+            table.store(n.wrapping_mul(0x9e37_79b9_7f4a_7c15), entry("e2e4", 0, 1));
+        }
+        assert!(table.hashfull() > 900);
+
+        table.new_search();
+        assert_eq!(table.hashfull(), 0);
+    }
+}
@@ -0,0 +1,336 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chess::{Board, ChessMove};
+
+use crate::position::Position;
+use crate::time_manager::TimeControl;
+
+// Piece placement, side to move, castling rights, en passant square, halfmove clock, fullmove number.
+const MAX_FEN_FIELDS: usize = 6;
+
+const GO_KEYWORDS: [&str; 12] = [
+    "searchmoves", "ponder", "wtime", "btime", "winc", "binc", "movestogo", "depth", "nodes", "mate", "movetime", "infinite",
+];
+
+/// A command sent by the GUI, checked completely before any of it is applied.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Uci,
+    IsReady,
+    UciNewGame,
+    /// `moves` are legal when played one after another from `position`.
+    Position { position: Position, moves: Vec<ChessMove> },
+    Go(GoCommand),
+    SetOption { name: String, value: Option<String> },
+    Stop,
+    PonderHit,
+    Quit,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct GoCommand {
+    pub time_control: TimeControl,
+    pub depth: Option<u16>,
+    pub nodes: Option<u64>,
+    pub mate: Option<u16>,
+    pub infinite: bool,
+    pub ponder: bool,
+    /// Legal in the position the command was parsed for.
+    pub search_moves: Vec<ChessMove>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UciError {
+    EmptyCommand,
+    UnknownCommand(String),
+    UnexpectedToken(String),
+    MissingValue(&'static str),
+    InvalidValue { name: String, value: String },
+    InvalidFen(String),
+    InvalidMove(String),
+    IllegalMove(String),
+}
+
+impl fmt::Display for UciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciError::EmptyCommand => write!(f, "empty command"),
+            UciError::UnknownCommand(command) => write!(f, "unknown command {command}"),
+            UciError::UnexpectedToken(token) => write!(f, "unexpected token {token}"),
+            UciError::MissingValue(name) => write!(f, "missing value for {name}"),
+            UciError::InvalidValue { name, value } => write!(f, "invalid value {value} for {name}"),
+            UciError::InvalidFen(fen) => write!(f, "invalid fen {fen}"),
+            UciError::InvalidMove(mov) => write!(f, "invalid move {mov}"),
+            UciError::IllegalMove(mov) => write!(f, "illegal move {mov}"),
+        }
+    }
+}
+
+/// Parses one line from the GUI. `board` is the current position, "go searchmoves" is checked
+/// against it.
+pub fn parse_command(line: &str, board: &Board) -> Result<Command, UciError> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let Some((&command, args)) = tokens.split_first() else {
+        return Err(UciError::EmptyCommand);
+    };
+
+    let no_args = |command: Command| {
+        match args.first() {
+            Some(token) => Err(UciError::UnexpectedToken(token.to_string())),
+            None => Ok(command),
+        }
+    };
+
+    match command {
+        "uci" => no_args(Command::Uci),
+        "isready" => no_args(Command::IsReady),
+        "ucinewgame" => no_args(Command::UciNewGame),
+        "stop" => no_args(Command::Stop),
+        "ponderhit" => no_args(Command::PonderHit),
+        "quit" => no_args(Command::Quit),
+        "position" => parse_position(args),
+        "go" => parse_go(args, board).map(Command::Go),
+        "setoption" => parse_setoption(args),
+        _ => Err(UciError::UnknownCommand(command.to_string())),
+    }
+}
+
+/// position (startpos | fen <1-6 fields>) [moves <move>...]
+fn parse_position(args: &[&str]) -> Result<Command, UciError> {
+    let moves_index = args.iter().position(|&token| token == "moves").unwrap_or(args.len());
+    let (setup, moves) = args.split_at(moves_index);
+
+    let position = match setup.split_first() {
+        Some((&"startpos", [])) => Position::default(),
+        Some((&"startpos", [token, ..])) => return Err(UciError::UnexpectedToken(token.to_string())),
+        Some((&"fen", [])) => return Err(UciError::MissingValue("fen")),
+        Some((&"fen", fields)) if fields.len() > MAX_FEN_FIELDS => {
+            return Err(UciError::UnexpectedToken(fields[MAX_FEN_FIELDS].to_string()))
+        }
+        Some((&"fen", fields)) => {
+            let fen = fields.join(" ");
+            Position::from_fen(&fen).ok_or(UciError::InvalidFen(fen))?
+        }
+        Some((token, _)) => return Err(UciError::UnexpectedToken(token.to_string())),
+        None => return Err(UciError::MissingValue("position")),
+    };
+
+    let mut current = position.board;
+    let mut parsed_moves = Vec::new();
+    for uci_move in moves.iter().skip(1) {
+        let mov = parse_move(uci_move, &current)?;
+        current = current.make_move_new(mov);
+        parsed_moves.push(mov);
+    }
+
+    Ok(Command::Position { position, moves: parsed_moves })
+}
+
+fn parse_go(args: &[&str], board: &Board) -> Result<GoCommand, UciError> {
+    let mut go = GoCommand::default();
+    let mut tokens = args.iter().copied().peekable();
+
+    while let Some(keyword) = tokens.next() {
+        match keyword {
+            "infinite" => go.infinite = true,
+            "ponder" => go.ponder = true,
+            "searchmoves" => {
+                while let Some(uci_move) = tokens.next_if(|token| !GO_KEYWORDS.contains(token)) {
+                    go.search_moves.push(parse_move(uci_move, board)?);
+                }
+            }
+            "wtime" => go.time_control.white_time = Some(parse_time("wtime", tokens.next())?),
+            "btime" => go.time_control.black_time = Some(parse_time("btime", tokens.next())?),
+            "winc" => go.time_control.white_increment = parse_time("winc", tokens.next())?,
+            "binc" => go.time_control.black_increment = parse_time("binc", tokens.next())?,
+            "movetime" => go.time_control.move_time = Some(parse_time("movetime", tokens.next())?),
+            "movestogo" => go.time_control.moves_to_go = Some(parse_value("movestogo", tokens.next())?),
+            "depth" => go.depth = Some(parse_value("depth", tokens.next())?),
+            "nodes" => go.nodes = Some(parse_value("nodes", tokens.next())?),
+            "mate" => go.mate = Some(parse_value("mate", tokens.next())?),
+            _ => return Err(UciError::UnexpectedToken(keyword.to_string())),
+        }
+    }
+
+    Ok(go)
+}
+
+/// setoption name <id> [value <x>], both the name and the value may contain spaces.
+fn parse_setoption(args: &[&str]) -> Result<Command, UciError> {
+    match args.first() {
+        Some(&"name") => {}
+        Some(token) => return Err(UciError::UnexpectedToken(token.to_string())),
+        None => return Err(UciError::MissingValue("name")),
+    }
+
+    let value_index = args.iter().position(|&token| token == "value");
+    let name = args[1..value_index.unwrap_or(args.len())].join(" ");
+    if name.is_empty() {
+        return Err(UciError::MissingValue("name"));
+    }
+
+    let value = match value_index {
+        Some(value_index) if value_index + 1 == args.len() => return Err(UciError::MissingValue("value")),
+        Some(value_index) => Some(args[value_index + 1..].join(" ")),
+        None => None,
+    };
+
+    Ok(Command::SetOption { name, value })
+}
+
+fn parse_move(uci_move: &str, board: &Board) -> Result<ChessMove, UciError> {
+    if uci_move.len() != 4 && uci_move.len() != 5 {
+        return Err(UciError::InvalidMove(uci_move.to_string()));
+    }
+    let mov = ChessMove::from_str(uci_move).map_err(|_| UciError::InvalidMove(uci_move.to_string()))?;
+    if !board.legal(mov) {
+        return Err(UciError::IllegalMove(uci_move.to_string()));
+    }
+    Ok(mov)
+}
+
+fn parse_value<T: FromStr>(name: &'static str, value: Option<&str>) -> Result<T, UciError> {
+    let value = value.ok_or(UciError::MissingValue(name))?;
+    value.parse().map_err(|_| UciError::InvalidValue { name: name.to_string(), value: value.to_string() })
+}
+
+/// Parses a time in milliseconds, some GUIs send negative values once the clock has run out.
+fn parse_time(name: &'static str, value: Option<&str>) -> Result<u64, UciError> {
+    parse_value::<i64>(name, value).map(|time| time.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::Square;
+
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, UciError> {
+        parse_command(line, &Board::default())
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(parse("uci"), Ok(Command::Uci));
+        assert_eq!(parse("  isready  "), Ok(Command::IsReady));
+        assert_eq!(parse("ucinewgame"), Ok(Command::UciNewGame));
+        assert_eq!(parse("stop"), Ok(Command::Stop));
+        assert_eq!(parse("ponderhit"), Ok(Command::PonderHit));
+        assert_eq!(parse("quit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn empty_and_unknown_commands() {
+        assert_eq!(parse(""), Err(UciError::EmptyCommand));
+        assert_eq!(parse("   \t "), Err(UciError::EmptyCommand));
+        assert_eq!(parse("xyzzy 1 2"), Err(UciError::UnknownCommand("xyzzy".to_string())));
+        assert_eq!(parse("isready now"), Err(UciError::UnexpectedToken("now".to_string())));
+    }
+
+    #[test]
+    fn position_startpos_with_moves() {
+        let e4 = ChessMove::new(Square::E2, Square::E4, None);
+        let e5 = ChessMove::new(Square::E7, Square::E5, None);
+        assert_eq!(parse("position startpos moves e2e4 e7e5"), Ok(Command::Position { position: Position::default(), moves: vec![e4, e5] }));
+        assert_eq!(parse("position startpos moves"), Ok(Command::Position { position: Position::default(), moves: vec![] }));
+    }
+
+    #[test]
+    fn position_fen_with_counters() {
+        let Ok(Command::Position { position, moves }) = parse("position fen 4k3/8/8/8/8/8/4P3/4K3 w - - 12 34 moves e2e4") else {
+            panic!("position was not parsed");
+        };
+        assert_eq!((position.halfmove_clock, position.fullmove_number), (12, 34));
+        assert_eq!(moves, vec![ChessMove::new(Square::E2, Square::E4, None)]);
+
+        for fen in ["4k3/8/8/8/8/8/8/4K3", "4k3/8/8/8/8/8/8/4K3 w", "4k3/8/8/8/8/8/8/4K3 w -", "4k3/8/8/8/8/8/8/4K3 w - -"] {
+            let Ok(Command::Position { position, .. }) = parse(&format!("position fen {fen}")) else {
+                panic!("{fen} was not parsed");
+            };
+            assert_eq!((position.halfmove_clock, position.fullmove_number), (0, 1));
+        }
+
+        let Ok(Command::Position { position, .. }) = parse("position fen 4k3/8/8/8/8/8/8/4K3 b - - 7") else {
+            panic!("position was not parsed");
+        };
+        assert_eq!((position.halfmove_clock, position.fullmove_number), (7, 1));
+    }
+
+    #[test]
+    fn malformed_position() {
+        assert_eq!(parse("position"), Err(UciError::MissingValue("position")));
+        assert_eq!(parse("position fen"), Err(UciError::MissingValue("fen")));
+        assert_eq!(parse("position moves e2e4"), Err(UciError::MissingValue("position")));
+        assert_eq!(parse("position start"), Err(UciError::UnexpectedToken("start".to_string())));
+        assert_eq!(parse("position startpos e2e4"), Err(UciError::UnexpectedToken("e2e4".to_string())));
+        assert!(matches!(parse("position fen not/a/fen w - -"), Err(UciError::InvalidFen(_))));
+        assert!(matches!(parse("position fen 8/8/8/8/8/8/8/8 w - - 0 1"), Err(UciError::InvalidFen(_))));
+        assert!(matches!(parse("position fen 4k3/8/8/8/8/8/8/4K2K w - - 0 1"), Err(UciError::InvalidFen(_))));
+        assert!(matches!(parse("position fen 4k3/8/8/8/8/8/8/4K3 x - - 0 1"), Err(UciError::InvalidFen(_))));
+        assert!(matches!(parse("position fen 4k3/8/8/8/8/8/8/4K3 w - - half 1"), Err(UciError::InvalidFen(_))));
+        assert!(matches!(parse("position fen 4k3/8/8/8/8/8/8/4K3 w - - 0 1.5 moves"), Err(UciError::InvalidFen(_))));
+        assert_eq!(parse("position fen 4k3/8/8/8/8/8/8/4K3 w - - 0 1 e1e2"), Err(UciError::UnexpectedToken("e1e2".to_string())));
+    }
+
+    #[test]
+    fn illegal_and_invalid_moves() {
+        assert_eq!(parse("position startpos moves e2e5"), Err(UciError::IllegalMove("e2e5".to_string())));
+        assert_eq!(parse("position startpos moves e2e4 e2e4"), Err(UciError::IllegalMove("e2e4".to_string())));
+        assert_eq!(parse("position startpos moves e2"), Err(UciError::InvalidMove("e2".to_string())));
+        assert_eq!(parse("position startpos moves e2e4x"), Err(UciError::InvalidMove("e2e4x".to_string())));
+        assert_eq!(parse("position startpos moves i2i4"), Err(UciError::InvalidMove("i2i4".to_string())));
+        assert_eq!(parse("position startpos moves ä2e4"), Err(UciError::InvalidMove("ä2e4".to_string())));
+    }
+
+    #[test]
+    fn go_with_clock() {
+        let Ok(Command::Go(go)) = parse("go wtime 1000 btime -20 winc 10 binc 20 movestogo 5") else {
+            panic!("go was not parsed");
+        };
+        assert_eq!(go.time_control.white_time, Some(1000));
+        assert_eq!(go.time_control.black_time, Some(0));
+        assert_eq!(go.time_control.white_increment, 10);
+        assert_eq!(go.time_control.black_increment, 20);
+        assert_eq!(go.time_control.moves_to_go, Some(5));
+    }
+
+    #[test]
+    fn go_with_searchmoves() {
+        let Ok(Command::Go(go)) = parse("go searchmoves e2e4 d2d4 depth 3 infinite") else {
+            panic!("go was not parsed");
+        };
+        assert_eq!(go.search_moves, vec![ChessMove::new(Square::E2, Square::E4, None), ChessMove::new(Square::D2, Square::D4, None)]);
+        assert_eq!(go.depth, Some(3));
+        assert!(go.infinite);
+    }
+
+    #[test]
+    fn malformed_go() {
+        assert_eq!(parse("go depth"), Err(UciError::MissingValue("depth")));
+        assert_eq!(parse("go depth -3"), Err(UciError::InvalidValue { name: "depth".to_string(), value: "-3".to_string() }));
+        assert_eq!(parse("go nodes many"), Err(UciError::InvalidValue { name: "nodes".to_string(), value: "many".to_string() }));
+        assert_eq!(parse("go wtime"), Err(UciError::MissingValue("wtime")));
+        assert_eq!(parse("go movetime 1.5"), Err(UciError::InvalidValue { name: "movetime".to_string(), value: "1.5".to_string() }));
+        assert_eq!(parse("go fast"), Err(UciError::UnexpectedToken("fast".to_string())));
+        assert_eq!(parse("go searchmoves e2e5"), Err(UciError::IllegalMove("e2e5".to_string())));
+    }
+
+    #[test]
+    fn setoption() {
+        assert_eq!(parse("setoption name Clear Hash"), Ok(Command::SetOption { name: "Clear Hash".to_string(), value: None }));
+        assert_eq!(
+            parse("setoption name Move Overhead value 100"),
+            Ok(Command::SetOption { name: "Move Overhead".to_string(), value: Some("100".to_string()) })
+        );
+    }
+
+    #[test]
+    fn malformed_setoption() {
+        assert_eq!(parse("setoption"), Err(UciError::MissingValue("name")));
+        assert_eq!(parse("setoption name"), Err(UciError::MissingValue("name")));
+        assert_eq!(parse("setoption name value 3"), Err(UciError::MissingValue("name")));
+        assert_eq!(parse("setoption name Hash value"), Err(UciError::MissingValue("value")));
+        assert_eq!(parse("setoption Hash value 3"), Err(UciError::UnexpectedToken("Hash".to_string())));
+    }
+}